@@ -0,0 +1,65 @@
+#!/usr/bin/env node
+// Renders a formula to SVG from Node, with the same output as 'maths_preview -f svg'.
+// Usage: node cli.js -i FORMULA [-o OUTFILE] [-s FONTSIZE] [-m MATHFONT] [--faceindex N] [--styfile STYFILE] [-d]
+const fs = require("fs");
+const {
+	init_font,
+	init_font_from_bytes,
+	render_formula_to_svg_with_metainfo,
+} = require("./maths_preview.js");
+
+function parseArgs(argv) {
+	const options = { metainfo: false };
+	for (let i = 0; i < argv.length; i++) {
+		const arg = argv[i];
+		switch (arg) {
+			case "-i": case "--informula": options.informula = argv[++i]; break;
+			case "-o": case "--outfile":   options.outfile   = argv[++i]; break;
+			case "-s": case "--fontsize":  options.fontsize  = argv[++i]; break;
+			case "-m": case "--mathfont":  options.mathfont  = argv[++i]; break;
+			case "--faceindex":            options.faceindex = Number(argv[++i]); break;
+			case "--styfile":              options.styfile   = argv[++i]; break;
+			case "-d": case "--metainfo":  options.metainfo  = true; break;
+			default:
+				throw new Error(`Unknown option: ${arg}`);
+		}
+	}
+	if (options.informula === undefined) {
+		throw new Error("No formula given (use -i)");
+	}
+	return options;
+}
+
+function main() {
+	const options = parseArgs(process.argv.slice(2));
+
+	const context = options.mathfont === undefined
+		? init_font()
+		: init_font_from_bytes(fs.readFileSync(options.mathfont), options.faceindex);
+	if (options.fontsize !== undefined) {
+		context.set_settings_from_js(false, options.fontsize);
+	}
+	if (options.styfile !== undefined) {
+		context.set_style_file(fs.readFileSync(options.styfile, "utf8"));
+	}
+
+	const { svg, metainfo } = render_formula_to_svg_with_metainfo(context, options.informula);
+	if (options.outfile === undefined) {
+		process.stdout.write(svg);
+	}
+	else {
+		fs.writeFileSync(options.outfile, svg);
+	}
+	if (options.metainfo) {
+		console.log(JSON.stringify(metainfo));
+	}
+	context.free();
+}
+
+try {
+	main();
+}
+catch (error) {
+	console.error(error.message);
+	process.exit(1);
+}
@@ -1,4 +1,13 @@
 pub mod app;
 pub mod cli;
+mod config;
+pub mod headless;
+mod history;
+mod html;
+mod markdown;
+mod mdbook;
+mod pandoc;
+mod snippets;
 pub mod ui;
-mod undo;
\ No newline at end of file
+mod undo;
+mod watch;
\ No newline at end of file
@@ -1,13 +1,13 @@
 use std::cell::{RefCell, Cell};
 use std::collections::{HashMap, HashSet};
 use std::io::Write;
+use std::path::PathBuf;
 use std::rc::Rc;
 
 
 use rex::font::common::GlyphId;
 use rex::font::backend::ttf_parser::TtfMathFont;
 use rex::layout::engine::LayoutBuilder;
-use rex::parser::macros::CommandCollection;
 use rex::parser::parse_with_custom_commands;
 use rex::Renderer;
 use serde_json;
@@ -18,43 +18,58 @@ use serde_json;
 use crate::error::AppResult;
 use crate::desktop::cli::{Format, Output, DEFAULT_FONT, EXAMPLE_FORMULA, UI_FONT_SIZE};
 use crate::render::{MetaInfo, render_svg};
+use crate::svg::SvgContext;
 use crate::geometry::Metrics;
 use crate::error::AppError;
 use crate::geometry::BBox;
 use crate::glyph_to_character::collect_chars;
+use crate::font_loading::pick_face_index;
+use crate::macros::CustomCommands;
+use crate::desktop::undo::DEFAULT_UNDO_DEPTH;
 
 
 #[derive(Clone)]
 pub struct AppContext {
     pub math_font  : Rc<Cell<& 'static [u8]>>,
+    pub face_index : Rc<Cell<Option<u32>>>,
     pub format     : Rc<Cell<Format>>,
     pub font_size  : Rc<Cell<f64>>,
-    pub custom_cmd : Rc<RefCell<CommandCollection>>,
+    pub custom_cmd : Rc<RefCell<CustomCommands>>,
+    pub styfiles   : Rc<RefCell<Vec<PathBuf>>>,
+    /// Errors of the style files which could not be loaded at startup
+    pub styfile_errors : Rc<RefCell<Vec<AppError>>>,
     pub outfile    : Rc<RefCell<Output>>,
     pub informula  : Rc<RefCell<String>>,
     pub metainfo   : Rc<Cell<bool>>,
+    pub follow     : Rc<RefCell<Option<PathBuf>>>,
+    pub undo_depth : Rc<Cell<usize>>,
 }
 
 impl Default for AppContext {
     fn default() -> Self {
         Self {
             math_font:  Rc::new(Cell::new(DEFAULT_FONT)),
+            face_index: Rc::new(Cell::new(None)),
             format:     Rc::new(Cell::default()),
             font_size:  Rc::new(Cell::new(UI_FONT_SIZE)),
             outfile:    Rc::new(RefCell::default()),
             informula:  Rc::new(RefCell::new(EXAMPLE_FORMULA.to_string())),
             metainfo:   Rc::new(Cell::new(false)),
             custom_cmd: Rc::default(),
+            styfiles:   Rc::default(),
+            styfile_errors: Rc::default(),
+            follow:     Rc::default(),
+            undo_depth: Rc::new(Cell::new(DEFAULT_UNDO_DEPTH)),
         }
     }
 }
 
-pub fn save_to_output(text: &str, outfile: &Output, format : Format, font : Rc<TtfMathFont>, font_size : f64, print_metainfo : bool, custom_cmd : &CommandCollection) -> AppResult<()> {
+pub fn save_to_output(text: &str, outfile: &Output, format : Format, font : Rc<TtfMathFont>, font_size : f64, print_metainfo : bool, custom_cmd : &CustomCommands) -> AppResult<()> {
     eprintln!("Saving to {:?}", outfile);
 
     match format {
         Format::Svg { glyph_as_text } => {
-            let (metrics, svg_string) = render_svg(&text, font.as_ref(), font_size, custom_cmd, glyph_as_text)?;
+            let (metrics, svg_string) = render_svg(&text, None, font.as_ref(), font_size, custom_cmd, glyph_as_text, SvgContext::DEFAULT_COLOR)?;
             outfile.stream()?.write(svg_string.as_bytes())?;
 
             if print_metainfo {
@@ -72,6 +87,11 @@ pub fn save_to_output(text: &str, outfile: &Output, format : Format, font : Rc<T
 }
 
 
+pub fn load_font<'a>(file : &'a [u8], face_index : Option<u32>) -> AppResult<TtfMathFont<'a>> {
+    let font = ttf_parser::Face::parse(file, pick_face_index(file, face_index))?;
+    Ok(TtfMathFont::new(font)?)
+}
+
 fn save_tex(outfile: &Output, text: &str) -> AppResult<()> {
     outfile.stream()?.write(text.as_bytes())?;
     Ok(())
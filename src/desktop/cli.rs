@@ -1,7 +1,5 @@
 use std::io::Write;
-use std::path::PathBuf;
-
-use rex::parser::macros::CommandCollection;
+use std::path::{Path, PathBuf};
 
 use gtk4::glib::VariantDict;
 use gtk4::prelude::*;
@@ -9,8 +7,11 @@ use gtk4::Application;
 
 
 
-use crate::error::AppResult;
+use crate::error::{AppError, AppResult};
+use crate::font_loading::decompress;
+use crate::macros::CustomCommands;
 use crate::desktop::app::AppContext;
+use crate::desktop::config;
 
 
 pub(crate) const EXAMPLE_FORMULA : &str = r"\left.x^{x^{x^x_x}_{x^x_x}}_{x^{x^x_x}_{x^x_x}}\right\} \mathrm{wat?}";
@@ -58,7 +59,16 @@ pub fn setup_command_line(application: &Application) {
         gtk4::glib::Char(b'm' as i8), 
         gtk4::glib::OptionFlags::IN_MAIN, 
         gtk4::glib::OptionArg::Filename, 
-        "Path to an OpenType maths font to use for render (default: STIX Maths, bundled in the executable)", 
+        "Path to an OpenType maths font to use for render, possibly compressed as WOFF or WOFF2 (default: Libertinus Math, bundled in the executable). For font collections (.ttc, .otc), a face can be picked by appending '#' and its index to the path, e.g. 'fonts.ttc#1'.", 
+        None,
+    );
+
+    application.add_main_option(
+        "faceindex", 
+        gtk4::glib::Char(0), 
+        gtk4::glib::OptionFlags::IN_MAIN, 
+        gtk4::glib::OptionArg::Int, 
+        "Index of the face to use if 'mathfont' is a font collection (default: the first face with a MATH table)", 
         None,
     );
 
@@ -84,11 +94,38 @@ pub fn setup_command_line(application: &Application) {
         "styfile", 
         gtk4::glib::Char(b'y' as i8), 
         gtk4::glib::OptionFlags::IN_MAIN, 
+        gtk4::glib::OptionArg::FilenameArray, 
+        "Reads a style file to provide custom command ; the file is read again whenever it changes. The option can be repeated, later files taking precedence over earlier ones. Style files in the 'maths_preview' folder of the user configuration folder (e.g. '~/.config/maths_preview/*.sty') are always read first.", 
+        None,
+    );
+
+
+    application.add_main_option(
+        "follow", 
+        gtk4::glib::Char(0), 
+        gtk4::glib::OptionFlags::IN_MAIN, 
         gtk4::glib::OptionArg::Filename, 
-        "Reads a style file to provide custom command", 
+        "Previews the formula of a file, e.g. edited in another text editor, instead of editing a formula. The preview is updated whenever the file changes ; the formula field is read-only.", 
         None,
     );
 
+    application.add_main_option(
+        "profile", 
+        gtk4::glib::Char(b'p' as i8), 
+        gtk4::glib::OptionFlags::IN_MAIN, 
+        gtk4::glib::OptionArg::String, 
+        "Name of a profile of the configuration file ('[profiles.NAME]'), whose settings take precedence over the other settings of the file", 
+        None,
+    );
+
+    application.add_main_option(
+        "undodepth", 
+        gtk4::glib::Char(0), 
+        gtk4::glib::OptionFlags::IN_MAIN, 
+        gtk4::glib::OptionArg::Int, 
+        "Maximal number of edits which can be undone (default: 100). The undo history is saved when the app is closed, and restored when the app is opened again on the same formula.", 
+        None,
+    );
 
     application.add_main_option(
         "metainfo", 
@@ -127,22 +164,53 @@ pub fn setup_command_line(application: &Application) {
     );
 }
 
-fn parse_path(option : &VariantDict) -> AppResult<Option<& 'static [u8]>> {
-    if let Some(mathfont) = option.lookup_value("mathfont", None) {
-        if let Some(path) = mathfont.try_get::<PathBuf>().ok() {
-            let font_bytes = std::fs::read(&path)?;
-            // TODO: find a more elegant way to deal with lifetimes.
-            // The lifetime in TtfMathFont & the requirement that closures fed to GTK are 'static come in conflict.
-            // We leak the memory of the box so as to get a 'static reference.
-            // This is ok, because we only leak once, but it's somewhat inelegant.
-            Ok(Some(Box::leak(font_bytes.into_boxed_slice())))
-        }
-        else { Ok(None) }
+fn parse_path(option : &VariantDict) -> AppResult<Option<(& 'static [u8], Option<u32>)>> {
+    if let Some((path, face_index)) = mathfont_path(option) {
+        let font_bytes = read_font(&path)?;
+        // TODO: find a more elegant way to deal with lifetimes.
+        // The lifetime in TtfMathFont & the requirement that closures fed to GTK are 'static come in conflict.
+        // We leak the memory of the box so as to get a 'static reference.
+        // This is ok, because we only leak once, but it's somewhat inelegant.
+        Ok(Some((Box::leak(font_bytes.into_boxed_slice()), face_index)))
     }
     else { Ok(None) }
 
 }
 
+/// Path of the 'mathfont' option, with the face index it may end with
+pub(crate) fn mathfont_path(option : &VariantDict) -> Option<(PathBuf, Option<u32>)> {
+    let path = option.lookup_value("mathfont", None)?.try_get::<PathBuf>().ok()?;
+    Some(split_face_index(path))
+}
+
+/// Reads a font file, decompressing WOFF and WOFF2 fonts
+pub(crate) fn read_font(path : &Path) -> AppResult<Vec<u8>> {
+    Ok(decompress(&std::fs::read(path)?)?.into_owned())
+}
+
+/// Splits a path of the form 'fonts.ttc#1' into the path proper and the face index.
+/// Paths to existing files are left untouched, in case their name contains a '#'.
+fn split_face_index(path : PathBuf) -> (PathBuf, Option<u32>) {
+    if path.exists() {
+        return (path, None);
+    }
+    let split = path.to_str().and_then(|path_str| {
+        let (file, index) = path_str.rsplit_once('#')?;
+        Some((PathBuf::from(file), index.parse().ok()?))
+    });
+    match split {
+        Some((file, index)) => (file, Some(index)),
+        None                => (path, None),
+    }
+}
+
+fn parse_face_index(option : &VariantDict) -> AppResult<Option<u32>> {
+    let Some(face_index) = option.lookup_value("faceindex", None) else { return Ok(None); };
+    let Ok(result) = face_index.try_get::<i32>() else { return Ok(None); };
+    let face_index = u32::try_from(result).map_err(|_| AppError::ParseError(format!("Invalid face index: {} (face indices start at 0)", result)))?;
+    Ok(Some(face_index))
+}
+
 fn parse_outfile(option : &VariantDict) -> Output {
     fn aux(option : &VariantDict) -> Option<PathBuf> {
         let outfile = option.lookup_value("outfile", None)?;
@@ -162,15 +230,70 @@ fn parse_format(option : &VariantDict) -> Option<Format> {
 }
 
 
-fn parse_styfile(option : &VariantDict) -> AppResult<Option<CommandCollection>> {
-    if let Some(styfile) = option.lookup_value("styfile", None) {
-        if let Ok(sty_filepath) = styfile.try_get::<PathBuf>() {
-            let sty_file = std::fs::read_to_string(&sty_filepath)?;
-            Ok(Some(CommandCollection::parse(&sty_file)?))
+/// Style files to read, in order of increasing precedence: those of the default search path, then those of the 'styfile' option
+pub(crate) fn styfile_paths(option : &VariantDict) -> Vec<PathBuf> {
+    let mut paths = default_styfiles();
+    if let Some(styfiles) = option.lookup_value("styfile", None) {
+        paths.extend(styfiles.try_get::<Vec<PathBuf>>().unwrap_or_default());
+    }
+    paths
+}
+
+/// The '.sty' files of the configuration folder of the app, in alphabetical order
+fn default_styfiles() -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(config::config_dir()) else { return Vec::new(); };
+    let mut paths : Vec<PathBuf> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().map(|extension| extension == "sty").unwrap_or(false))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Reads and merges style files ; a warning is printed for every command which a file redefines
+pub(crate) fn read_styfiles(paths : &[PathBuf]) -> AppResult<CustomCommands> {
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        files.push((path.display().to_string(), std::fs::read_to_string(path)?));
+    }
+    merge_styfiles(&files)
+}
+
+/// Like 'read_styfiles', but the files which can't be read or parsed are left out.
+/// Returns the commands of the other files, along with the errors.
+fn read_valid_styfiles(paths : &[PathBuf]) -> (CustomCommands, Vec<AppError>) {
+    let mut files = Vec::with_capacity(paths.len());
+    let mut errors = Vec::new();
+    for path in paths {
+        let label = path.display().to_string();
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                errors.push(AppError::IOError(std::io::Error::new(e.kind(), format!("{}: {}", label, e))));
+                continue;
+            },
+        };
+        // the file is parsed on its own first, so that its errors don't spoil the other files
+        match CustomCommands::layered([(label.as_str(), source.as_str())]) {
+            Ok(_)  => files.push((label, source)),
+            Err(e) => errors.push(e),
         }
-        else { Ok(None) }
     }
-    else { Ok(None) }
+    match merge_styfiles(&files) {
+        Ok(custom_cmd) => (custom_cmd, errors),
+        Err(e) => {
+            errors.push(e);
+            (CustomCommands::default(), errors)
+        },
+    }
+}
+
+fn merge_styfiles(files : &[(String, String)]) -> AppResult<CustomCommands> {
+    let (custom_cmd, warnings) = CustomCommands::layered(files.iter().map(|(label, source)| (label.as_str(), source.as_str())))?;
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    Ok(custom_cmd)
 }
 
 
@@ -187,14 +310,36 @@ fn parse_in_formula(option : &VariantDict) -> Option<String> {
     Some(result)
 }
 
+fn parse_follow(option : &VariantDict) -> Option<PathBuf> {
+    option.lookup_value("follow", None)?.try_get::<PathBuf>().ok()
+}
+
+fn parse_undo_depth(option : &VariantDict) -> Option<usize> {
+    let undo_depth = option.lookup_value("undodepth", None)?.try_get::<i32>().ok()?;
+    undo_depth.try_into().ok()
+}
+
 fn parse_metainfo(option : &VariantDict) -> bool {
     option.lookup_value("metainfo", None).is_some()
 }
 
 pub fn handle_options(app_context : &AppContext, option : &VariantDict) -> std::ops::ControlFlow<gtk4::glib::ExitCode> {
-	let AppContext {math_font,format,font_size,outfile,informula,metainfo,custom_cmd, } = app_context;
+	// options of the command line take precedence over the configuration file
+	if let Err(e) = config::read_config(option) {
+	    eprintln!("{}", e);
+	    std::process::exit(1);
+	}
+	apply_options(app_context, option)
+}
+
+/// Like 'handle_options', for options which already include the settings of the configuration file
+pub(crate) fn apply_options(app_context : &AppContext, option : &VariantDict) -> std::ops::ControlFlow<gtk4::glib::ExitCode> {
+	let AppContext {math_font,face_index,format,font_size,outfile,informula,metainfo,custom_cmd,styfiles,styfile_errors,follow,undo_depth, } = app_context;
 	match parse_path(option) {
-	    Ok(Some(font_file)) => math_font.set(font_file),
+	    Ok(Some((font_file, path_face_index))) => {
+	        math_font.set(font_file);
+	        face_index.set(path_face_index);
+	    },
 	    Err(e) => {
 	        eprintln!("{}", e);
 	        // FIXME: for whatever reason, GTK ignores the exit status code here?
@@ -204,6 +349,14 @@ pub fn handle_options(app_context : &AppContext, option : &VariantDict) -> std::
 	    },
 	    Ok(None) => (),
 	}
+	match parse_face_index(option) {
+	    Ok(Some(face_index_arg)) => face_index.set(Some(face_index_arg)),
+	    Err(e) => {
+	        eprintln!("{}", e);
+	        std::process::exit(1);
+	    },
+	    Ok(None) => (),
+	}
 	*outfile.borrow_mut() = parse_outfile(option);
 	if let Some(option_format) = parse_format(option) {
 	    format.set(option_format);
@@ -214,16 +367,19 @@ pub fn handle_options(app_context : &AppContext, option : &VariantDict) -> std::
 	if let Some(formula) = parse_in_formula(option) {
 	    *informula.borrow_mut() = formula;
 	} 
-	match parse_styfile(option) {
-	    Ok(Some(new_custom_cmd)) => *custom_cmd.borrow_mut() = new_custom_cmd,
-	    Err(e) => {
-	        eprintln!("{}", e);
-	        // FIXME: for whatever reason, GTK ignores the exit status code here?
-	        // We resort to something more brutal
-	        // return 1;
-	        std::process::exit(1);
-	    },
-	    Ok(None) => (),
+	// a broken style file, e.g. in the configuration folder, should not prevent the app from starting: the errors are shown once it runs
+	let sty_filepaths = styfile_paths(option);
+	if !sty_filepaths.is_empty() {
+	    let (new_custom_cmd, errors) = read_valid_styfiles(&sty_filepaths);
+	    *custom_cmd.borrow_mut() = new_custom_cmd;
+	    *styfile_errors.borrow_mut() = errors;
+	}
+	*styfiles.borrow_mut() = sty_filepaths;
+	if let Some(depth) = parse_undo_depth(option) {
+	    undo_depth.set(depth);
+	}
+	if let Some(path) = parse_follow(option) {
+	    *follow.borrow_mut() = Some(path);
 	}
 	if parse_metainfo(option) {
 	    metainfo.set(true);
@@ -0,0 +1,94 @@
+use std::path::{Path, PathBuf};
+
+use gtk4::glib::{Variant, VariantDict};
+use gtk4::prelude::ToVariant;
+use serde_json::Value;
+
+use crate::error::{AppError, AppResult};
+
+
+
+/// '$XDG_CONFIG_HOME/maths_preview' or its equivalent on other platforms
+pub(crate) fn config_dir() -> PathBuf {
+    gtk4::glib::user_config_dir().join("maths_preview")
+}
+
+/// Reads the configuration file, 'config.toml' in the configuration folder, into the options which were not given on the command line.
+/// Settings have the same names as the options of the command line ; profiles are tables of settings which take precedence over the others:
+///
+/// ```toml
+/// mathfont = "fonts/LibertinusMath-Regular.otf"
+/// format = "svg"
+/// styfile = ["team.sty", "personal.sty"]
+///
+/// [profiles.slides]
+/// fontsize = 24
+/// glyphastext = true
+/// ```
+///
+/// Paths are relative to the configuration folder.
+pub fn read_config(options : &VariantDict) -> AppResult<()> {
+    let profile = options.lookup_value("profile", None).and_then(|profile| profile.get::<String>());
+    let path = config_dir().join("config.toml");
+    let source = match std::fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && profile.is_none() => return Ok(()),
+        Err(e) => return Err(AppError::IOError(std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))),
+    };
+    let mut settings : Value = toml::from_str(&source).map_err(|e| AppError::ParseError(format!("{}: {}", path.display(), e)))?;
+
+    // the settings of the profile replace those of the file before any of them is applied, so that 'false' in a profile can turn off a flag of the file
+    if let Some(profile) = profile {
+        let profile_settings = settings.get("profiles").and_then(|profiles| profiles.get(&profile)).and_then(Value::as_object).cloned()
+            .ok_or_else(|| AppError::ParseError(format!("{}: no profile named '{}'", path.display(), profile)))?;
+        if let Value::Object(settings) = &mut settings {
+            settings.extend(profile_settings);
+        }
+    }
+    // options given on the command line are not overwritten
+    apply_settings(&settings, &config_dir(), options, false);
+    Ok(())
+}
+
+/// Inserts settings, e.g. from a configuration file, into the options dictionary ; unknown settings are ignored.
+/// Relative paths are relative to 'root' ; unless 'overwrite' is set, options already in the dictionary are kept.
+pub(crate) fn apply_settings(settings : &Value, root : &Path, options : &VariantDict, overwrite : bool) {
+    let insert = |name : &str, value : Variant| {
+        if overwrite || !options.contains(name) {
+            options.insert_value(name, &value);
+        }
+    };
+
+    if let Some(path) = settings.get("mathfont").and_then(Value::as_str) {
+        insert("mathfont", root.join(path).to_variant());
+    }
+    // either a path or a list of paths
+    let styfiles : Vec<PathBuf> = match settings.get("styfile") {
+        Some(Value::String(path)) => vec![root.join(path)],
+        Some(Value::Array(paths)) => paths.iter().filter_map(Value::as_str).map(|path| root.join(path)).collect(),
+        _                         => Vec::new(),
+    };
+    if !styfiles.is_empty() {
+        insert("styfile", styfiles.to_variant());
+    }
+    if let Some(font_size) = settings.get("fontsize").and_then(Value::as_f64) {
+        insert("fontsize", font_size.to_variant());
+    }
+    if let Some(face_index) = settings.get("faceindex").and_then(Value::as_i64).and_then(|index| i32::try_from(index).ok()) {
+        insert("faceindex", face_index.to_variant());
+    }
+    if let Some(undo_depth) = settings.get("undodepth").and_then(Value::as_i64).and_then(|depth| i32::try_from(depth).ok()) {
+        insert("undodepth", undo_depth.to_variant());
+    }
+    if let Some(format) = settings.get("format").and_then(Value::as_str) {
+        insert("format", format.to_variant());
+    }
+    // flags of the command line are either there or not: 'false' can only remove a flag when overwriting
+    for flag in ["glyphastext", "metainfo"] {
+        match settings.get(flag).and_then(Value::as_bool) {
+            Some(true)              => insert(flag, true.to_variant()),
+            Some(false) if overwrite => { options.remove(flag); },
+            _                       => (),
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use std::io::Read;
+use std::path::PathBuf;
+
+use gtk4::glib::{Variant, VariantDict};
+use gtk4::prelude::ToVariant;
+use rex::font::backend::ttf_parser::TtfMathFont;
+
+use crate::error::AppResult;
+use crate::desktop::app::{AppContext, load_font};
+use crate::desktop::cli::{self, Format};
+use crate::desktop::{config, html, markdown, mdbook, pandoc};
+use crate::desktop::watch::Watcher;
+use crate::geometry::Metrics;
+use crate::render::{render_svg, FormulaStyle};
+use crate::svg::SvgContext;
+
+
+
+/// Subcommands which process documents without opening a window, e.g. 'maths_preview markdown notes.md'
+#[derive(Debug, Clone, Copy)]
+enum Subcommand {
+    Markdown,
+    Pandoc,
+    Mdbook,
+    Html,
+    Watch,
+}
+
+impl Subcommand {
+    fn from_name(name : &str) -> Option<Self> {
+        match name {
+            "markdown" => Some(Self::Markdown),
+            "pandoc"   => Some(Self::Pandoc),
+            "mdbook"   => Some(Self::Mdbook),
+            "html"     => Some(Self::Html),
+            "watch"    => Some(Self::Watch),
+            _          => None,
+        }
+    }
+
+    fn usage(self) -> &'static str {
+        match self {
+            Self::Markdown => "maths_preview markdown [OPTIONS] [INPUT] : renders $...$ and $$...$$ in a Markdown file (default: stdin) as inline SVG",
+            Self::Pandoc   => "maths_preview pandoc [OPTIONS] [FORMAT] : Pandoc JSON filter, renders the maths of the document read on stdin for output format FORMAT",
+            Self::Mdbook   => "maths_preview mdbook [OPTIONS] | maths_preview mdbook supports RENDERER : mdBook preprocessor, renders the maths of the book read on stdin",
+            Self::Html     => "maths_preview html [OPTIONS] [INPUT] : replaces math elements and \\(...\\), \\[...\\] in an HTML file (default: stdin) by inline SVG",
+            Self::Watch    => "maths_preview watch [OPTIONS] INPUT : renders the formulas of INPUT (one per line, or a whole '.tex' snippet) to SVG every time INPUT, the style file or the font changes",
+        }
+    }
+}
+
+
+/// If the program was called with a subcommand, runs it and returns the exit code.
+/// Returns None if the GUI should be started instead.
+pub fn run_subcommand() -> Option<i32> {
+    let args : Vec<String> = std::env::args().skip(1).collect();
+    // 'pandoc --filter maths_preview' calls the filter with the output format as only argument.
+    // Pandoc sets 'PANDOC_VERSION' for every program it runs though, e.g. from Lua filters, which may call us with other arguments.
+    let called_as_filter = std::env::var_os("PANDOC_VERSION").is_some()
+        && matches!(args.as_slice(), [format] if !format.starts_with('-'));
+    let mut args = args.into_iter().peekable();
+    let subcommand = if called_as_filter {
+        // 'maths_preview pandoc', with no output format
+        if args.peek().map(String::as_str) == Some("pandoc") {
+            args.next();
+        }
+        Subcommand::Pandoc
+    }
+    else {
+        let subcommand = Subcommand::from_name(args.peek()?)?;
+        args.next();
+        subcommand
+    };
+
+    if let Subcommand::Mdbook = subcommand {
+        if args.peek().map(String::as_str) == Some("supports") {
+            args.next();
+            return Some(if mdbook::supports(args.next().as_deref()) { 0 } else { 1 });
+        }
+    }
+
+    let (options, input) = match parse_arguments(args) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!("Usage: {}", subcommand.usage());
+            eprintln!("Options: --mathfont (-m), --faceindex, --styfile (-y), --fontsize (-s), --glyphastext (-t), --outfile (-o), --profile (-p) ; see 'maths_preview --help'");
+            return Some(2);
+        },
+    };
+    // options of the command line take precedence over the configuration file
+    if let Err(e) = config::read_config(&options) {
+        eprintln!("{}", e);
+        return Some(1);
+    }
+
+    let result = match subcommand {
+        Subcommand::Markdown => checked_app_context(&options).and_then(|app_context| run_markdown(&app_context, input)),
+        // the positional argument is the output format, the document is read on stdin
+        Subcommand::Pandoc   => checked_app_context(&options).and_then(|app_context| run_pandoc(&app_context, input.as_ref().and_then(|format| format.to_str()))),
+        Subcommand::Mdbook   => run_mdbook(&options),
+        Subcommand::Html     => checked_app_context(&options).and_then(|app_context| run_html(&app_context, input)),
+        Subcommand::Watch    => run_watch(&options, input),
+    };
+
+    match result {
+        Ok(()) => Some(0),
+        Err(e) => {
+            eprintln!("{}", e);
+            Some(1)
+        },
+    }
+}
+
+fn run_markdown(app_context : &AppContext, input : Option<PathBuf>) -> AppResult<()> {
+    let renderer = FormulaRenderer::new(app_context)?;
+    let source = read_input(input)?;
+    let output = markdown::render_math(&source, |formula, style| renderer.render_html(formula, style))?;
+    app_context.outfile.borrow().stream()?.write_all(output.as_bytes())?;
+    Ok(())
+}
+
+fn run_pandoc(app_context : &AppContext, output_format : Option<&str>) -> AppResult<()> {
+    let renderer = FormulaRenderer::new(app_context)?;
+    let mut document : serde_json::Value = serde_json::from_str(&read_input(None)?).map_err(std::io::Error::from)?;
+    pandoc::filter(&mut document, output_format, &renderer);
+    let output = serde_json::to_string(&document).map_err(std::io::Error::from)?;
+    app_context.outfile.borrow().stream()?.write_all(output.as_bytes())?;
+    Ok(())
+}
+
+fn run_html(app_context : &AppContext, input : Option<PathBuf>) -> AppResult<()> {
+    let renderer = FormulaRenderer::new(app_context)?;
+    let source = read_input(input)?;
+    let output = html::render_math(&source, |formula, style| {
+        // the source is kept, e.g. for copy-pasting or re-rendering
+        let class = match style {
+            FormulaStyle::Text    => "math inline",
+            FormulaStyle::Display => "math display",
+        };
+        Ok(format!(r#"<span class="{}" data-latex="{}">{}</span>"#, class, escape_html(formula), renderer.render_html(formula, style)?))
+    })?;
+    app_context.outfile.borrow().stream()?.write_all(output.as_bytes())?;
+    Ok(())
+}
+
+fn run_watch(options : &VariantDict, input : Option<PathBuf>) -> AppResult<()> {
+    let input = input.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "'watch' needs an input file"))?;
+    // the watcher loads the font and the style files itself, including those of the configuration file, so that broken files don't end the program
+    let font_path = cli::mathfont_path(options);
+    let styfiles = cli::styfile_paths(options);
+    options.remove("mathfont");
+    options.remove("styfile");
+    Watcher::new(input, font_path, styfiles, app_context_from_options(options)).run()
+}
+
+fn run_mdbook(options : &VariantDict) -> AppResult<()> {
+    let [context, mut book] : [serde_json::Value; 2] = serde_json::from_str(&read_input(None)?).map_err(std::io::Error::from)?;
+    // settings from 'book.toml' take precedence over the command line
+    mdbook::read_settings(&context, options);
+    let app_context = checked_app_context(options)?;
+
+    let renderer = FormulaRenderer::new(&app_context)?;
+    mdbook::render_book(&mut book, &renderer)?;
+    let output = serde_json::to_string(&book).map_err(std::io::Error::from)?;
+    app_context.outfile.borrow().stream()?.write_all(output.as_bytes())?;
+    Ok(())
+}
+
+fn read_input(input : Option<PathBuf>) -> AppResult<String> {
+    match input {
+        Some(path) => Ok(std::fs::read_to_string(path)?),
+        None => {
+            let mut source = String::new();
+            std::io::stdin().read_to_string(&mut source)?;
+            Ok(source)
+        },
+    }
+}
+
+
+/// Parses the options of a subcommand into the same dictionary as GTK makes of the options of the GUI.
+/// Returns the options and the input file, if any.
+fn parse_arguments(args : impl Iterator<Item = String>) -> Result<(VariantDict, Option<PathBuf>), String> {
+    let options = VariantDict::new(None);
+    let mut input = None;
+
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') || arg == "-" {
+            if input.replace(PathBuf::from(arg)).is_some() {
+                return Err("Only one input file can be given".to_string());
+            }
+            continue;
+        }
+
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None                => (arg.clone(), None),
+        };
+        let option = OPTIONS.iter()
+            .find(|option| name == format!("--{}", option.long) || option.short.map(|short| name == format!("-{}", short)).unwrap_or(false))
+            .ok_or_else(|| format!("Unknown option: {}", name))?;
+
+        if let OptionKind::Flag = option.kind {
+            options.insert_value(option.long, &true.to_variant());
+            continue;
+        }
+        let value = inline_value
+            .or_else(|| args.next())
+            .ok_or_else(|| format!("Missing value for {}", name))?;
+        if let OptionKind::Filenames = option.kind {
+            let mut paths = options.lookup_value(option.long, None)
+                .and_then(|paths| paths.try_get::<Vec<PathBuf>>().ok())
+                .unwrap_or_default();
+            paths.push(PathBuf::from(value));
+            options.insert_value(option.long, &paths.to_variant());
+            continue;
+        }
+        options.insert_value(option.long, &option.kind.parse(&value).ok_or_else(|| format!("Invalid value for {}: {}", name, value))?);
+    }
+
+    // '-' stands for stdin
+    Ok((options, input.filter(|path| path.as_os_str() != "-")))
+}
+
+fn app_context_from_options(options : &VariantDict) -> AppContext {
+    // Subcommands always produce SVG
+    let glyph_as_text = options.lookup_value("glyphastext", None).is_some();
+
+    let app_context = AppContext::default();
+    let _ = cli::apply_options(&app_context, options);
+    app_context.format.set(Format::Svg { glyph_as_text });
+    app_context
+}
+
+/// Like 'app_context_from_options', but style files which could not be loaded are an error:
+/// unlike the GUI and 'watch', subcommands run once, with no chance for the files to be fixed.
+fn checked_app_context(options : &VariantDict) -> AppResult<AppContext> {
+    let app_context = app_context_from_options(options);
+    let first_error = app_context.styfile_errors.borrow_mut().drain(..).next();
+    match first_error {
+        Some(error) => Err(error),
+        None        => Ok(app_context),
+    }
+}
+
+enum OptionKind {
+    Filename,
+    /// Repeatable option, e.g. '-y a.sty -y b.sty'
+    Filenames,
+    String,
+    Int,
+    Double,
+    Flag,
+}
+
+impl OptionKind {
+    fn parse(&self, value : &str) -> Option<Variant> {
+        match self {
+            Self::Filename => Some(PathBuf::from(value).to_variant()),
+            Self::String   => Some(value.to_variant()),
+            Self::Int      => value.parse::<i32>().ok().map(|value| value.to_variant()),
+            Self::Double   => value.parse::<f64>().ok().map(|value| value.to_variant()),
+            Self::Filenames | Self::Flag => None,
+        }
+    }
+}
+
+struct OptionSpec {
+    long  : &'static str,
+    short : Option<char>,
+    kind  : OptionKind,
+}
+
+/// Options of the GUI which make sense for subcommands ; names are the same as in 'cli::setup_command_line'
+const OPTIONS : &[OptionSpec] = &[
+    OptionSpec { long: "mathfont",    short: Some('m'), kind: OptionKind::Filename },
+    OptionSpec { long: "faceindex",   short: None,      kind: OptionKind::Int },
+    OptionSpec { long: "styfile",     short: Some('y'), kind: OptionKind::Filenames },
+    OptionSpec { long: "fontsize",    short: Some('s'), kind: OptionKind::Double },
+    OptionSpec { long: "glyphastext", short: Some('t'), kind: OptionKind::Flag },
+    OptionSpec { long: "outfile",     short: Some('o'), kind: OptionKind::Filename },
+    OptionSpec { long: "profile",     short: Some('p'), kind: OptionKind::String },
+];
+
+
+
+/// Renders formulas to SVG with the font and settings of an app context.
+pub(crate) struct FormulaRenderer<'a> {
+    font : TtfMathFont<'static>,
+    app_context : &'a AppContext,
+}
+
+impl<'a> FormulaRenderer<'a> {
+    pub fn new(app_context : &'a AppContext) -> AppResult<Self> {
+        let font = load_font(app_context.math_font.get(), app_context.face_index.get())?;
+        Ok(Self { font, app_context })
+    }
+
+    pub fn render_svg(&self, formula : &str, style : FormulaStyle) -> AppResult<(Metrics, String)> {
+        let glyph_as_text = match self.app_context.format.get() {
+            Format::Svg { glyph_as_text } => glyph_as_text,
+            Format::Tex => false,
+        };
+        render_svg(
+            formula,
+            Some(style),
+            &self.font,
+            self.app_context.font_size.get(),
+            &self.app_context.custom_cmd.borrow(),
+            glyph_as_text,
+            SvgContext::DEFAULT_COLOR,
+        )
+    }
+
+    /// Renders the formula as an '<svg>' element to be embedded in HTML.
+    /// Inline formulas sit on the baseline of the surrounding text ; display formulas are centered on a line of their own.
+    pub fn render_html(&self, formula : &str, style : FormulaStyle) -> AppResult<String> {
+        let (metrics, svg) = self.render_svg(formula, style)?;
+        let svg = sized_svg(&metrics, &svg, &format!(r#"role="img" aria-label="{}""#, escape_html(formula)));
+        match style {
+            FormulaStyle::Text    => Ok(svg),
+            FormulaStyle::Display => Ok(format!(r#"<span style="display: block; text-align: center">{}</span>"#, svg)),
+        }
+    }
+}
+
+/// Adds explicit dimensions to the output of 'render_svg', so that the SVG can be embedded in a document.
+/// One SVG user unit is one point ; the SVG is shifted down by its depth, so that its baseline matches that of the surrounding text.
+pub(crate) fn sized_svg(metrics : &Metrics, svg : &str, extra_attributes : &str) -> String {
+    let attributes = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}pt" height="{}pt" style="vertical-align: {}pt" {} "#,
+        metrics.bbox.width(),
+        metrics.bbox.height(),
+        metrics.baseline,
+        extra_attributes,
+    );
+    svg.replacen("<svg ", &attributes, 1)
+}
+
+pub(crate) fn escape_html(text : &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&'  => escaped.push_str("&amp;"),
+            '<'  => escaped.push_str("&lt;"),
+            '>'  => escaped.push_str("&gt;"),
+            '"'  => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _    => escaped.push(character),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,85 @@
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppResult;
+use crate::desktop::cli::Format;
+
+
+
+/// A formula accepted when the app was closed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Seconds since the Unix epoch
+    pub timestamp : u64,
+    pub formula   : String,
+    /// Format of the output, e.g. 'svg' or 'tex'
+    pub format    : String,
+}
+
+impl HistoryEntry {
+    pub fn now(formula : &str, format : Format) -> Self {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+        let format = match format {
+            Format::Svg { .. } => "svg",
+            Format::Tex        => "tex",
+        };
+        Self { timestamp, formula: formula.to_string(), format: format.to_string() }
+    }
+}
+
+/// '$XDG_DATA_HOME/maths_preview/history.jsonl' or its equivalent on other platforms ; the history has one JSON entry per line
+fn history_path() -> PathBuf {
+    gtk4::glib::user_data_dir().join("maths_preview").join("history.jsonl")
+}
+
+/// Adds an entry to the history, which is trimmed to the 'limit' most recent formulas ; earlier occurrences of the formula are removed
+pub fn append(entry : &HistoryEntry, limit : usize) -> AppResult<()> {
+    let path = history_path();
+    if let Some(folder) = path.parent() {
+        std::fs::create_dir_all(folder)?;
+    }
+    let mut entries = read_entries(&path);
+    entries.retain(|previous_entry| previous_entry.formula != entry.formula);
+    entries.push(entry.clone());
+
+    let mut lines = String::new();
+    for entry in &entries[entries.len().saturating_sub(limit) ..] {
+        lines.push_str(&serde_json::to_string(entry).map_err(std::io::Error::from)?);
+        lines.push('\n');
+    }
+    // the file is replaced at once, so that a crash while writing does not lose the history
+    let temporary_path = path.with_extension("jsonl.tmp");
+    std::fs::write(&temporary_path, lines)?;
+    std::fs::rename(temporary_path, path)?;
+    Ok(())
+}
+
+/// The most recent entries first, with only the last occurrence of each formula ; at most 'limit' entries
+pub fn load(limit : usize) -> Vec<HistoryEntry> {
+    let entries = read_entries(&history_path());
+
+    let mut recent_entries : Vec<HistoryEntry> = Vec::new();
+    for entry in entries.into_iter().rev() {
+        if recent_entries.len() >= limit {
+            break;
+        }
+        if !recent_entries.iter().any(|recent_entry| recent_entry.formula == entry.formula) {
+            recent_entries.push(entry);
+        }
+    }
+    recent_entries
+}
+
+/// Entries of the history file, oldest first
+fn read_entries(path : &Path) -> Vec<HistoryEntry> {
+    let Ok(file) = std::fs::File::open(path) else { return Vec::new(); };
+    // lines which can't be read (e.g. an entry cut short by a crash) are skipped
+    std::io::BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect()
+}
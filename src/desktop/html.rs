@@ -0,0 +1,335 @@
+use crate::error::AppResult;
+use crate::render::FormulaStyle;
+
+
+
+/// Elements whose content is not HTML ; formulas are not looked for inside them
+const RAW_TEXT_ELEMENTS : &[&str] = &["script", "style", "textarea", "pre", "code"];
+
+
+/// Replaces the maths of an HTML page by the output of 'render'.
+/// Maths are either elements '<span class="math inline">\(...\)</span>' and '<span class="math display">\[...\]</span>' (as produced by Pandoc),
+/// or '\(...\)' and '\[...\]' in text (as understood by MathJax).
+/// The whole math element is replaced ; the output of 'render' receives the formula unescaped and without delimiters.
+pub fn render_math<F>(html : &str, mut render : F) -> AppResult<String>
+where F : FnMut(&str, FormulaStyle) -> AppResult<String>
+{
+    let mut output = String::with_capacity(html.len());
+    let bytes = html.as_bytes();
+    let mut index = 0;
+    // end of the part of 'html' already copied to 'output'
+    let mut copied = 0;
+
+    while index < bytes.len() {
+        if html[index ..].starts_with("<!--") {
+            index = html[index ..].find("-->").map(|end| index + end + 3).unwrap_or(bytes.len());
+        }
+        else if bytes[index] == b'<' {
+            let Some(tag_end) = find_tag_end(bytes, index) else { break; };
+            let tag = &html[index .. tag_end];
+            let name = tag_name(tag);
+
+            if RAW_TEXT_ELEMENTS.contains(&name.as_str()) {
+                // jump to the closing tag
+                index = find_raw_text_end(html, tag_end, &name);
+                continue;
+            }
+
+            let math_element = match name.as_str() {
+                "span" => math_span_style(tag).and_then(|style| Some((style, find_closing_tag(html, tag_end, "span")?))),
+                _      => None,
+            };
+            match math_element {
+                Some((style, (content_end, element_end))) => {
+                    let formula = unescape_html(&strip_tags(&html[tag_end .. content_end]));
+                    output.push_str(&html[copied .. index]);
+                    output.push_str(&render(strip_delimiters(formula.trim()), style)?);
+                    index = element_end;
+                    copied = index;
+                },
+                None => index = tag_end,
+            }
+        }
+        else if let Some((style, closing_delimiter)) = opening_delimiter(&html[index ..]) {
+            let content_start = index + 2;
+            // formulas in text can't contain tags
+            let text_end = html[content_start ..].find('<').map(|end| content_start + end).unwrap_or(bytes.len());
+            match html[content_start .. text_end].find(closing_delimiter) {
+                Some(content_length) => {
+                    let content_end = content_start + content_length;
+                    let formula = unescape_html(&html[content_start .. content_end]);
+                    output.push_str(&html[copied .. index]);
+                    output.push_str(&render(&formula, style)?);
+                    index = content_end + 2;
+                    copied = index;
+                },
+                None => index = content_start,
+            }
+        }
+        else {
+            // skip a whole character, so that slicing stays on char boundaries
+            index += html[index ..].chars().next().map(char::len_utf8).unwrap_or(1);
+        }
+    }
+
+    output.push_str(&html[copied ..]);
+    Ok(output)
+}
+
+fn opening_delimiter(text : &str) -> Option<(FormulaStyle, &'static str)> {
+    if text.starts_with("\\(") {
+        Some((FormulaStyle::Text, "\\)"))
+    }
+    else if text.starts_with("\\[") {
+        Some((FormulaStyle::Display, "\\]"))
+    }
+    else {
+        None
+    }
+}
+
+fn strip_delimiters(formula : &str) -> &str {
+    for (opening, closing) in [("\\(", "\\)"), ("\\[", "\\]")] {
+        if let Some(inner) = formula.strip_prefix(opening).and_then(|formula| formula.strip_suffix(closing)) {
+            return inner;
+        }
+    }
+    formula
+}
+
+/// Finds the end (exclusive) of the tag starting at 'start', skipping '>' in quoted attribute values
+fn find_tag_end(bytes : &[u8], start : usize) -> Option<usize> {
+    let mut quote = None;
+    for (index, byte) in bytes.iter().enumerate().skip(start + 1) {
+        match (quote, byte) {
+            (None, b'"' | b'\'') => quote = Some(*byte),
+            (Some(opening), _) if opening == *byte => quote = None,
+            (None, b'>') => return Some(index + 1),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Lower-cased name of an opening tag ; closing tags, doctypes, etc. have names starting with '/' or '!'
+fn tag_name(tag : &str) -> String {
+    tag[1 ..]
+        .split(|character : char| character.is_ascii_whitespace() || character == '>')
+        .next()
+        .unwrap_or("")
+        // self-closing tags, e.g. '<br/>'
+        .trim_end_matches('/')
+        .to_ascii_lowercase()
+}
+
+/// Finds the closing tag matching an element opened just before 'start', taking nested elements of the same name into account.
+/// Returns the start and end of the closing tag.
+fn find_closing_tag(html : &str, start : usize, name : &str) -> Option<(usize, usize)> {
+    let mut depth = 0;
+    let mut index = start;
+    while let Some(offset) = html[index ..].find('<') {
+        let tag_start = index + offset;
+        let tag_end = find_tag_end(html.as_bytes(), tag_start)?;
+        let tag_name = tag_name(&html[tag_start .. tag_end]);
+        if tag_name == name {
+            depth += 1;
+        }
+        else if tag_name.strip_prefix('/') == Some(name) {
+            if depth == 0 {
+                return Some((tag_start, tag_end));
+            }
+            depth -= 1;
+        }
+        index = tag_end;
+    }
+    None
+}
+
+/// Finds the start of the closing tag of a raw text element (e.g. '</script>'), whose content can't contain tags
+fn find_raw_text_end(html : &str, start : usize, name : &str) -> usize {
+    let mut index = start;
+    while let Some(offset) = html[index ..].find("</") {
+        let tag_start = index + offset;
+        let after_slash = &html.as_bytes()[tag_start + 2 ..];
+        if after_slash.len() >= name.len() && after_slash[.. name.len()].eq_ignore_ascii_case(name.as_bytes()) {
+            return tag_start;
+        }
+        index = tag_start + 2;
+    }
+    html.len()
+}
+
+/// Style of a '<span class="math inline">' or '<span class="math display">' tag, None for other tags
+fn math_span_style(tag : &str) -> Option<FormulaStyle> {
+    let classes = attribute(tag, "class")?;
+    let mut classes = classes.split_ascii_whitespace();
+    if !classes.clone().any(|class| class == "math") {
+        return None;
+    }
+    if classes.any(|class| class == "display") {
+        Some(FormulaStyle::Display)
+    }
+    else {
+        Some(FormulaStyle::Text)
+    }
+}
+
+/// Value of an attribute of a tag, e.g. 'attribute(r#"<span class="math">"#, "class") == Some("math")'
+fn attribute<'a>(tag : &'a str, name : &str) -> Option<&'a str> {
+    let is_name_end = |character : char| character.is_ascii_whitespace() || "=/>".contains(character);
+    // skip '<' and the tag name
+    let mut rest = tag[1 ..].trim_start_matches(|character| !is_name_end(character));
+    loop {
+        rest = rest.trim_start_matches(|character : char| character.is_ascii_whitespace() || character == '/');
+        if rest.is_empty() || rest.starts_with('>') {
+            return None;
+        }
+        let name_end = rest.find(is_name_end).unwrap_or(rest.len());
+        let attribute_name = &rest[.. name_end];
+        rest = rest[name_end ..].trim_start();
+
+        let mut value = "";
+        if let Some(after_equal) = rest.strip_prefix('=') {
+            let after_equal = after_equal.trim_start();
+            let (attribute_value, after_value) = match after_equal.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let quoted = &after_equal[1 ..];
+                    let length = quoted.find(quote).unwrap_or(quoted.len());
+                    (&quoted[.. length], &quoted[(length + 1).min(quoted.len()) ..])
+                },
+                _ => {
+                    let length = after_equal.find(|character : char| character.is_ascii_whitespace() || character == '>').unwrap_or(after_equal.len());
+                    (&after_equal[.. length], &after_equal[length ..])
+                },
+            };
+            value = attribute_value;
+            rest = after_value;
+        }
+
+        if attribute_name.eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+    }
+}
+
+fn strip_tags(html : &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for character in html.chars() {
+        match character {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(character),
+            _ => (),
+        }
+    }
+    text
+}
+
+fn unescape_html(text : &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(ampersand) = rest.find('&') {
+        unescaped.push_str(&rest[.. ampersand]);
+        rest = &rest[ampersand ..];
+        let entity = rest.find(';').map(|end| &rest[1 .. end]);
+        let character = entity.and_then(|entity| match entity {
+            "amp"  => Some('&'),
+            "lt"   => Some('<'),
+            "gt"   => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ => {
+                let code = entity.strip_prefix('#')?;
+                let code = match code.strip_prefix(['x', 'X']) {
+                    Some(hexadecimal) => u32::from_str_radix(hexadecimal, 16).ok()?,
+                    None              => code.parse().ok()?,
+                };
+                char::from_u32(code)
+            },
+        });
+        match (character, entity) {
+            (Some(character), Some(entity)) => {
+                unescaped.push(character);
+                rest = &rest[entity.len() + 2 ..];
+            },
+            _ => {
+                unescaped.push('&');
+                rest = &rest[1 ..];
+            },
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renders formulas as "[T:formula]" or "[D:formula]"
+    fn render(html : &str) -> String {
+        render_math(html, |formula, style| {
+            let tag = match style { FormulaStyle::Text => 'T', FormulaStyle::Display => 'D' };
+            Ok(format!("[{}:{}]", tag, formula))
+        }).unwrap()
+    }
+
+    #[test]
+    fn math_in_text() {
+        assert_eq!(render(r"<p>a \(x\) b \[y\]</p>"), "<p>a [T:x] b [D:y]</p>");
+        assert_eq!(render(r"<p>\(a &lt; b\)</p>"), "<p>[T:a < b]</p>");
+        // formulas in text can't contain tags
+        assert_eq!(render(r"<p>\(a <b>b</b>\)</p>"), r"<p>\(a <b>b</b>\)</p>");
+    }
+
+    #[test]
+    fn math_spans() {
+        assert_eq!(render(r#"<span class="math inline">\(x\)</span>"#), "[T:x]");
+        assert_eq!(render(r#"<span class="math display">\[x\]</span>"#), "[D:x]");
+        assert_eq!(render(r#"<span class="math display"><span>\[x</span>\]</span>!"#), "[D:x]!");
+        assert_eq!(render(r#"<span class="other">\(x\)</span>"#), r#"<span class="other">[T:x]</span>"#);
+    }
+
+    #[test]
+    fn class_attribute_quoting() {
+        assert_eq!(render(r"<span class='display math'>\[x\]</span>"), "[D:x]");
+        assert_eq!(render(r"<span id=a class=math>\(x\)</span>"), "[T:x]");
+        assert_eq!(attribute(r#"<span title="a>b" class = 'math inline'>"#, "class"), Some("math inline"));
+        assert_eq!(attribute(r"<input disabled class=math/>", "CLASS"), Some("math/"));
+        assert_eq!(attribute(r"<input disabled>", "disabled"), Some(""));
+        assert_eq!(attribute(r#"<span classes="math">"#, "class"), None);
+    }
+
+    #[test]
+    fn raw_text_is_untouched() {
+        for html in [
+            r"<!-- \(x\) -->",
+            r"<SCRIPT>let a = '\(x\)';</script>",
+            r"<code>\(x\)</code>",
+            r"<pre><code>\[x\]</code></pre>",
+            r#"<a title="\(x\)">link</a>"#,
+        ] {
+            assert_eq!(render(html), html);
+        }
+        assert_eq!(render(r"<code>\(x\)</CODE> \(y\)"), r"<code>\(x\)</CODE> [T:y]");
+    }
+
+    #[test]
+    fn unclosed_elements() {
+        // the delimiters still mark a formula
+        assert_eq!(render(r#"<span class="math inline">\(x\)"#), r#"<span class="math inline">[T:x]"#);
+        assert_eq!(render(r"<p \(x\)"), r"<p \(x\)");
+        assert_eq!(render(r"\(x"), r"\(x");
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(unescape_html("a&lt;b&#x3C;c&#60;d&amp;e"), "a<b<c<d&e");
+        assert_eq!(unescape_html("&foo; &#xzz; &#1114112; &amp"), "&foo; &#xzz; &#1114112; &amp");
+        assert_eq!(unescape_html("&nbsp;&quot;é&apos;"), "\u{a0}\"é'");
+    }
+}
@@ -0,0 +1,324 @@
+use crate::error::{AppError, AppResult};
+use crate::render::FormulaStyle;
+
+
+
+/// Replaces inline '$...$' and display '$$...$$' math in a Markdown document by the output of 'render'.
+/// Code spans, fenced code blocks and indented code blocks are left untouched, as are escaped dollars ('\$').
+/// Following Pandoc, an inline formula can't start with a space, nor end with a space or be directly followed by a digit (so that "$5 and $10" is not math).
+pub fn render_math<F>(markdown : &str, mut render : F) -> AppResult<String>
+where F : FnMut(&str, FormulaStyle) -> AppResult<String>
+{
+    let mut output = String::with_capacity(markdown.len());
+    let mut block = Block::Prose;
+    // start of the current block
+    let mut block_start = 0;
+    let mut line_start = 0;
+    // an indented code block can't interrupt a paragraph
+    let mut after_blank_line = true;
+
+    for line in markdown.split_inclusive('\n') {
+        let line_end = line_start + line.len();
+        let is_blank = line.trim().is_empty();
+
+        // an indented code block ends at the first line which is neither blank nor indented ; this line may start another block
+        if matches!(block, Block::Indented) && !is_blank && !is_indented(line) {
+            output.push_str(&markdown[block_start .. line_start]);
+            block = Block::Prose;
+            block_start = line_start;
+        }
+
+        match block {
+            Block::Prose => if let Some(opening_fence) = Fence::opening(line) {
+                let prose = &markdown[block_start .. line_start];
+                render_prose(prose, line_number(markdown, block_start), &mut render, &mut output)?;
+                block = Block::Fenced(opening_fence);
+                block_start = line_start;
+            }
+            else if after_blank_line && is_indented(line) {
+                let prose = &markdown[block_start .. line_start];
+                render_prose(prose, line_number(markdown, block_start), &mut render, &mut output)?;
+                block = Block::Indented;
+                block_start = line_start;
+            },
+            Block::Fenced(opening_fence) => if opening_fence.is_closed_by(line) {
+                // code blocks are copied verbatim
+                output.push_str(&markdown[block_start .. line_end]);
+                block = Block::Prose;
+                block_start = line_end;
+            },
+            Block::Indented => (),
+        }
+        after_blank_line = is_blank;
+        line_start = line_end;
+    }
+
+    match block {
+        // code blocks run to the end of the document, e.g. when a fence is never closed
+        Block::Fenced(_) | Block::Indented => output.push_str(&markdown[block_start ..]),
+        Block::Prose => render_prose(&markdown[block_start ..], line_number(markdown, block_start), &mut render, &mut output)?,
+    }
+    Ok(output)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Block {
+    Prose,
+    Fenced(Fence),
+    /// Code indented by 4 spaces or a tab
+    Indented,
+}
+
+fn is_indented(line : &str) -> bool {
+    line.starts_with('\t') || line.starts_with("    ")
+}
+
+fn line_number(text : &str, offset : usize) -> usize {
+    1 + text[.. offset].matches('\n').count()
+}
+
+/// Opening fence of a fenced code block, e.g. "```rust" or "~~~~"
+#[derive(Debug, Clone, Copy)]
+struct Fence {
+    character : u8,
+    length    : usize,
+}
+
+impl Fence {
+    fn opening(line : &str) -> Option<Self> {
+        let (character, length, rest) = Self::parse(line)?;
+        // the info string of backtick fences can't contain backticks
+        if character == b'`' && rest.contains('`') {
+            return None;
+        }
+        Some(Self { character, length })
+    }
+
+    fn is_closed_by(self, line : &str) -> bool {
+        match Self::parse(line) {
+            Some((character, length, rest)) => character == self.character && length >= self.length && rest.trim().is_empty(),
+            None => false,
+        }
+    }
+
+    /// Splits a fence line into the fence character, the length of the fence and the rest of the line
+    fn parse(line : &str) -> Option<(u8, usize, &str)> {
+        // fences can be indented by up to 3 spaces
+        let indent = line.bytes().take_while(|byte| *byte == b' ').count();
+        if indent > 3 {
+            return None;
+        }
+        let line = &line[indent ..];
+        let character = *line.as_bytes().first()?;
+        if character != b'`' && character != b'~' {
+            return None;
+        }
+        let length = line.bytes().take_while(|byte| *byte == character).count();
+        if length < 3 {
+            return None;
+        }
+        Some((character, length, &line[length ..]))
+    }
+}
+
+
+fn render_prose<F>(prose : &str, first_line : usize, render : &mut F, output : &mut String) -> AppResult<()>
+where F : FnMut(&str, FormulaStyle) -> AppResult<String>
+{
+    let bytes = prose.as_bytes();
+    let mut index = 0;
+    // end of the part of 'prose' already copied to 'output'
+    let mut copied = 0;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 2,
+            b'`'  => {
+                let length = run_length(bytes, index, b'`');
+                index = match find_closing_backticks(bytes, index + length, length) {
+                    Some(closing) => closing + length,
+                    // unmatched backticks are literal
+                    None          => index + length,
+                };
+            },
+            b'$'  => {
+                let style = if bytes.get(index + 1) == Some(&b'$') { FormulaStyle::Display } else { FormulaStyle::Text };
+                let delimiter_length = match style { FormulaStyle::Display => 2, FormulaStyle::Text => 1 };
+                let content_start = index + delimiter_length;
+                let content_end = match style {
+                    FormulaStyle::Display => find_display_end(bytes, content_start),
+                    FormulaStyle::Text    => find_inline_end(bytes, content_start),
+                };
+
+                match content_end {
+                    Some(content_end) => {
+                        output.push_str(&prose[copied .. index]);
+                        let formula = &prose[content_start .. content_end];
+                        let rendered = render(formula, style).map_err(|e| at_line(e, first_line + prose[.. index].matches('\n').count()))?;
+                        output.push_str(&rendered);
+                        index = content_end + delimiter_length;
+                        copied = index;
+                    },
+                    None => index = content_start,
+                }
+            },
+            _ => index += 1,
+        }
+    }
+
+    output.push_str(&prose[copied ..]);
+    Ok(())
+}
+
+fn at_line(error : AppError, line : usize) -> AppError {
+    match error {
+        AppError::ParseError(message) => AppError::ParseError(format!("{} (line {})", message, line)),
+        error => error,
+    }
+}
+
+fn run_length(bytes : &[u8], start : usize, byte : u8) -> usize {
+    bytes[start ..].iter().take_while(|b| **b == byte).count()
+}
+
+/// Finds a run of exactly 'length' backticks
+fn find_closing_backticks(bytes : &[u8], start : usize, length : usize) -> Option<usize> {
+    let mut index = start;
+    while index < bytes.len() {
+        if bytes[index] == b'`' {
+            let run = run_length(bytes, index, b'`');
+            if run == length {
+                return Some(index);
+            }
+            index += run;
+        }
+        else {
+            index += 1;
+        }
+    }
+    None
+}
+
+fn find_inline_end(bytes : &[u8], start : usize) -> Option<usize> {
+    if bytes.get(start).map(u8::is_ascii_whitespace).unwrap_or(true) {
+        return None;
+    }
+    let mut index = start;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 2,
+            b'\n' if starts_blank_line(bytes, index + 1) => return None,
+            b'$' => {
+                let after_space = bytes[index - 1].is_ascii_whitespace();
+                let before_digit = bytes.get(index + 1).map(u8::is_ascii_digit).unwrap_or(false);
+                if !after_space && !before_digit {
+                    return Some(index);
+                }
+                index += 1;
+            },
+            _ => index += 1,
+        }
+    }
+    None
+}
+
+fn find_display_end(bytes : &[u8], start : usize) -> Option<usize> {
+    let mut index = start;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 2,
+            b'\n' if starts_blank_line(bytes, index + 1) => return None,
+            b'$' if bytes.get(index + 1) == Some(&b'$') => return Some(index),
+            _ => index += 1,
+        }
+    }
+    None
+}
+
+/// Math can't span paragraphs
+fn starts_blank_line(bytes : &[u8], start : usize) -> bool {
+    bytes[start ..].iter()
+        .take_while(|byte| **byte != b'\n')
+        .all(u8::is_ascii_whitespace)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renders formulas as "[T:formula]" or "[D:formula]"
+    fn render(markdown : &str) -> String {
+        render_math(markdown, |formula, style| {
+            let tag = match style { FormulaStyle::Text => 'T', FormulaStyle::Display => 'D' };
+            Ok(format!("[{}:{}]", tag, formula))
+        }).unwrap()
+    }
+
+    #[test]
+    fn inline_and_display() {
+        assert_eq!(render("a $x$ b $$y$$ c"), "a [T:x] b [D:y] c");
+        assert_eq!(render("$$\nx\n$$"), "[D:\nx\n]");
+    }
+
+    #[test]
+    fn code_spans() {
+        assert_eq!(render("`$x$` $y$"), "`$x$` [T:y]");
+        assert_eq!(render("``a ` $x$`` $y$"), "``a ` $x$`` [T:y]");
+        // unmatched backticks are literal
+        assert_eq!(render("` $x$"), "` [T:x]");
+    }
+
+    #[test]
+    fn fenced_code_blocks() {
+        assert_eq!(render("```\n$a$\n```\n$b$"), "```\n$a$\n```\n[T:b]");
+        assert_eq!(render("~~~~ tex\n$a$\n~~~\n$b$\n~~~~\n$c$"), "~~~~ tex\n$a$\n~~~\n$b$\n~~~~\n[T:c]");
+        // an unclosed fence runs to the end of the document
+        assert_eq!(render("$a$\n```\n$b$"), "[T:a]\n```\n$b$");
+    }
+
+    #[test]
+    fn indented_code_blocks() {
+        assert_eq!(render("    $a$\n$b$"), "    $a$\n[T:b]");
+        assert_eq!(render("text\n\n\t$a$\n\n    $b$\n\n$c$"), "text\n\n\t$a$\n\n    $b$\n\n[T:c]");
+        // a code block can be followed directly by a fence
+        assert_eq!(render("\n    $a$\n```\n$b$\n```\n$c$"), "\n    $a$\n```\n$b$\n```\n[T:c]");
+        // indented lines continue a paragraph
+        assert_eq!(render("text\n    $a$"), "text\n    [T:a]");
+        // less than 4 spaces is not code
+        assert_eq!(render("   $a$"), "   [T:a]");
+    }
+
+    #[test]
+    fn escaped_dollars() {
+        assert_eq!(render(r"\$x$ $y$"), r"\$x$ [T:y]");
+        assert_eq!(render(r"$a\$b$"), r"[T:a\$b]");
+    }
+
+    #[test]
+    fn prices_are_not_math() {
+        assert_eq!(render("$5 and $10"), "$5 and $10");
+        assert_eq!(render("$ x$ $x $"), "$ x$ $x $");
+        assert_eq!(render("$x$5"), "$x$5");
+    }
+
+    #[test]
+    fn math_does_not_span_paragraphs() {
+        assert_eq!(render("$a\n\nb$"), "$a\n\nb$");
+        assert_eq!(render("$$a\n  \nb$$"), "$$a\n  \nb$$");
+        assert_eq!(render("$a\nb$"), "[T:a\nb]");
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        let result = render_math("a\n\n```\n$x$\n```\n$y$", |formula, _| match formula {
+            "y" => Err(AppError::ParseError("bad".to_string())),
+            _   => Ok(String::new()),
+        });
+        match result {
+            Err(AppError::ParseError(message)) => assert_eq!(message, "bad (line 6)"),
+            _ => panic!("expected a parse error"),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::path::Path;
+
+use gtk4::glib::VariantDict;
+use serde_json::Value;
+
+use crate::error::{AppError, AppResult};
+use crate::desktop::headless::FormulaRenderer;
+use crate::desktop::{config, markdown};
+
+
+
+/// Names under which the preprocessor may be declared in 'book.toml', e.g. '[preprocessor.maths-preview]'
+const PREPROCESSOR_NAMES : &[&str] = &["maths-preview", "maths_preview"];
+
+/// Formulas are rendered as inline SVG, which only HTML renderers understand
+pub fn supports(renderer : Option<&str>) -> bool {
+    renderer == Some("html")
+}
+
+/// Reads the settings of the preprocessor from the mdBook context into the options dictionary.
+/// Settings have the same names as the options of the command line:
+///
+/// ```toml
+/// [preprocessor.maths-preview]
+/// command = "maths_preview mdbook"
+/// mathfont = "fonts/LibertinusMath-Regular.otf"
+/// fontsize = 12
+/// styfile = ["macros.sty", "chapter-macros.sty"]
+/// glyphastext = true
+/// ```
+///
+/// Paths are relative to the root of the book.
+pub fn read_settings(context : &Value, options : &VariantDict) {
+    let root = context.get("root").and_then(Value::as_str).map(Path::new).unwrap_or(Path::new("."));
+    let Some(settings) = PREPROCESSOR_NAMES.iter()
+        .find_map(|name| context.pointer(&format!("/config/preprocessor/{}", name)))
+    else { return; };
+
+    config::apply_settings(settings, root, options, true);
+}
+
+/// Renders the maths of all chapters of the book, in place.
+pub fn render_book(book : &mut Value, renderer : &FormulaRenderer) -> AppResult<()> {
+    // mdBook 0.4 calls the list of chapters 'sections', later versions 'items'
+    for key in ["sections", "items"] {
+        if let Some(Value::Array(items)) = book.get_mut(key) {
+            render_items(items, renderer)?;
+        }
+    }
+    Ok(())
+}
+
+/// Book items are either '{"Chapter": {...}}', '"Separator"' or '{"PartTitle": "..."}'
+fn render_items(items : &mut [Value], renderer : &FormulaRenderer) -> AppResult<()> {
+    for item in items {
+        let Some(chapter) = item.get_mut("Chapter") else { continue; };
+
+        let chapter_path = chapter.get("path").and_then(Value::as_str).unwrap_or("<draft chapter>").to_string();
+        if let Some(Value::String(content)) = chapter.get_mut("content") {
+            *content = markdown::render_math(content, |formula, style| renderer.render_html(formula, style))
+                .map_err(|e| in_chapter(e, &chapter_path))?;
+        }
+        if let Some(Value::Array(sub_items)) = chapter.get_mut("sub_items") {
+            render_items(sub_items, renderer)?;
+        }
+    }
+    Ok(())
+}
+
+fn in_chapter(error : AppError, chapter_path : &str) -> AppError {
+    match error {
+        AppError::ParseError(message) => AppError::ParseError(format!("{}: {}", chapter_path, message)),
+        error => error,
+    }
+}
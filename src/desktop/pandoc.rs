@@ -0,0 +1,112 @@
+use serde_json::{json, Value};
+
+use crate::error::AppResult;
+use crate::desktop::headless::FormulaRenderer;
+use crate::render::FormulaStyle;
+
+
+
+/// Output formats of Pandoc which understand raw HTML
+const HTML_FORMATS : &[&str] = &[
+    "html", "html4", "html5", "chunkedhtml", "epub", "epub2", "epub3",
+    "revealjs", "slidy", "slideous", "dzslides", "s5",
+];
+
+/// Output formats of Pandoc with native support for maths ; formulas are left to them
+const MATH_FORMATS : &[&str] = &["latex", "beamer", "context", "typst"];
+
+
+/// Replaces the 'Math' nodes of a Pandoc document (in Pandoc's JSON format) by rendered SVGs.
+/// For HTML outputs, SVGs are inlined as 'RawInline' nodes ; for other outputs (e.g. docx, odt), they become 'Image' nodes with a data URI.
+/// Formulas which fail to render are reported on stderr and left as they are.
+pub fn filter(document : &mut Value, output_format : Option<&str>, renderer : &FormulaRenderer) {
+    // Pandoc passes formats with their extensions, e.g. 'html5+smart'
+    let output_format = output_format.map(|format| format.split(['+', '-']).next().unwrap_or(format));
+    if let Some(format) = output_format {
+        if MATH_FORMATS.contains(&format) {
+            return;
+        }
+    }
+    let as_html = output_format.map(|format| HTML_FORMATS.contains(&format)).unwrap_or(false);
+    replace_math(document, as_html, renderer);
+}
+
+fn replace_math(value : &mut Value, as_html : bool, renderer : &FormulaRenderer) {
+    match value {
+        Value::Object(node) => {
+            if node.get("t").and_then(Value::as_str) == Some("Math") {
+                if let Some((style, formula)) = math_content(node.get("c")) {
+                    let rendered = if as_html { raw_html(&formula, style, renderer) } else { image(&formula, style, renderer) };
+                    // one faulty formula should not cost the whole document
+                    match rendered {
+                        Ok(rendered) => *value = rendered,
+                        Err(e)       => eprintln!("{} (formula: {})", e, formula),
+                    }
+                    return;
+                }
+            }
+            for child in node.values_mut() {
+                replace_math(child, as_html, renderer);
+            }
+        },
+        Value::Array(children) => {
+            for child in children {
+                replace_math(child, as_html, renderer);
+            }
+        },
+        _ => (),
+    }
+}
+
+/// Reads the content of a 'Math' node: '[{"t": "InlineMath"}, "formula"]'
+fn math_content(content : Option<&Value>) -> Option<(FormulaStyle, String)> {
+    let [math_type, formula] = content?.as_array()?.as_slice() else { return None; };
+    let style = match math_type.get("t")?.as_str()? {
+        "InlineMath"  => FormulaStyle::Text,
+        "DisplayMath" => FormulaStyle::Display,
+        _             => return None,
+    };
+    Some((style, formula.as_str()?.to_string()))
+}
+
+fn raw_html(formula : &str, style : FormulaStyle, renderer : &FormulaRenderer) -> AppResult<Value> {
+    let html = renderer.render_html(formula, style)?;
+    Ok(json!({ "t": "RawInline", "c": ["html", html] }))
+}
+
+fn image(formula : &str, style : FormulaStyle, renderer : &FormulaRenderer) -> AppResult<Value> {
+    let (metrics, svg) = renderer.render_svg(formula, style)?;
+    let svg = crate::desktop::headless::sized_svg(&metrics, &svg, "");
+    let url = format!("data:image/svg+xml;base64,{}", base64(svg.as_bytes()));
+    let class = match style {
+        FormulaStyle::Text    => "inline",
+        FormulaStyle::Display => "display",
+    };
+    let attributes = json!([
+        "",
+        ["math", class],
+        [
+            ["width",  format!("{}pt", metrics.bbox.width())],
+            ["height", format!("{}pt", metrics.bbox.height())],
+        ],
+    ]);
+    let alt_text = json!([{ "t": "Str", "c": formula }]);
+    Ok(json!({ "t": "Image", "c": [attributes, alt_text, [url, ""]] }))
+}
+
+fn base64(bytes : &[u8]) -> String {
+    const ALPHABET : &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| group | u32::from(*byte) << (16 - 8 * i));
+        for i in 0 .. 4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize]));
+            }
+            else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
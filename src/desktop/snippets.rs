@@ -0,0 +1,216 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+
+use crate::error::{AppError, AppResult};
+use crate::desktop::config::config_dir;
+
+
+
+/// Named templates of formulas, e.g. 'matrix2x2 = "\begin{pmatrix} $1 & $2 \\ $3 & $4 \end{pmatrix}"'.
+/// '$1', '$2', etc. are placeholders, which Tab jumps to in order ; '$0' is where the cursor ends up, after all the others.
+#[derive(Debug, Default)]
+pub struct Snippets(HashMap<String, String>);
+
+impl Snippets {
+    /// Reads the snippets of 'snippets.toml' in the configuration folder, if there is one
+    pub fn load() -> AppResult<Self> {
+        let path = snippets_path();
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let snippets = toml::from_str(&source).map_err(|e| AppError::ParseError(format!("{}: {}", path.display(), e)))?;
+        Ok(Self(snippets))
+    }
+
+    pub fn expand(&self, name : &str) -> Option<Expansion> {
+        self.0.get(name).map(|template| Expansion::new(template))
+    }
+}
+
+fn snippets_path() -> PathBuf {
+    config_dir().join("snippets.toml")
+}
+
+
+/// A snippet with its placeholders removed
+#[derive(Debug)]
+pub struct Expansion {
+    pub text : String,
+    /// Positions of the placeholders in 'text', in characters, in the order Tab visits them
+    pub placeholders : Vec<i32>,
+}
+
+impl Expansion {
+    fn new(template : &str) -> Self {
+        let mut text = String::with_capacity(template.len());
+        let mut numbered_placeholders = Vec::new();
+        let mut position = 0;
+        let mut characters = template.chars().peekable();
+        while let Some(character) = characters.next() {
+            let number = match characters.peek().and_then(|next| next.to_digit(10)) {
+                Some(digit) if character == '$' => {
+                    characters.next();
+                    let mut number = digit;
+                    while let Some(digit) = characters.peek().and_then(|next| next.to_digit(10)) {
+                        number = 10 * number + digit;
+                        characters.next();
+                    }
+                    number
+                },
+                _ => {
+                    text.push(character);
+                    position += 1;
+                    continue;
+                },
+            };
+            numbered_placeholders.push((number, position));
+        }
+        // stable sort: a placeholder which occurs twice is visited at each occurrence
+        numbered_placeholders.sort_by_key(|(number, _)| (*number == 0, *number));
+        let placeholders = numbered_placeholders.into_iter().map(|(_, position)| position).collect();
+        Self { text, placeholders }
+    }
+}
+
+
+/// Positions, in the text of the formula field, of the placeholders of the last snippet that Tab has not visited yet.
+/// Positions, as well as the range of text where the snippet was inserted, are kept up to date as the text is edited.
+#[derive(Debug, Default)]
+pub struct Placeholders {
+    positions : VecDeque<i32>,
+    start     : i32,
+    end       : i32,
+}
+
+impl Placeholders {
+    /// Placeholders of a snippet inserted at 'start'
+    pub fn new(start : i32, expansion : &Expansion) -> Self {
+        Self {
+            positions : expansion.placeholders.iter().map(|position| start + position).collect(),
+            start,
+            end : start + expansion.text.chars().count() as i32,
+        }
+    }
+
+    /// Next placeholder, unless the cursor has left the snippet: Tab is then no longer meant for the snippet, whose placeholders are forgotten
+    pub fn next(&mut self, cursor : i32) -> Option<i32> {
+        if !(self.start ..= self.end).contains(&cursor) {
+            self.positions.clear();
+        }
+        self.positions.pop_front()
+    }
+
+    pub fn insert_text(&mut self, point : i32, length : i32) {
+        for position in self.positions.iter_mut() {
+            // text typed in a placeholder pushes the following ones
+            if *position >= point {
+                *position += length;
+            }
+        }
+        if point < self.start {
+            self.start += length;
+        }
+        // text typed at the end of the snippet belongs to it, e.g. after '$0'
+        if point <= self.end {
+            self.end += length;
+        }
+    }
+
+    pub fn delete_text(&mut self, start : i32, end : i32) {
+        if start <= self.start && self.end <= end {
+            // the whole snippet is deleted
+            self.positions.clear();
+        }
+        for position in self.positions.iter_mut().chain([&mut self.start, &mut self.end]) {
+            if *position >= end {
+                *position -= end - start;
+            }
+            else if *position > start {
+                *position = start;
+            }
+        }
+    }
+}
+
+/// Start of the name of a snippet ending at 'end' in 'text', i.e. of the letters, digits and underscores before 'end'.
+/// Names right after a backslash are commands, not snippets.
+pub fn name_start(text : &str, end : usize) -> Option<usize> {
+    let characters : Vec<char> = text.chars().take(end).collect();
+    let length = characters.iter().rev().take_while(|character| character.is_alphanumeric() || **character == '_').count();
+    let start = characters.len() - length;
+    if length == 0 || (start > 0 && characters[start - 1] == '\\') {
+        return None;
+    }
+    Some(start)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expansion() {
+        let expansion = Expansion::new(r"\begin{pmatrix} $1 & $2 \\ $3 & $4 \end{pmatrix}");
+        assert_eq!(expansion.text, r"\begin{pmatrix}  &  \\  &  \end{pmatrix}");
+        assert_eq!(expansion.placeholders, [16, 19, 23, 26]);
+    }
+
+    #[test]
+    fn placeholder_order() {
+        // '$0' comes last, '$10' after '$2'
+        let expansion = Expansion::new(r"\frac{$10}{$2}$0 + $2é");
+        assert_eq!(expansion.text, r"\frac{}{} + é");
+        assert_eq!(expansion.placeholders, [8, 12, 6, 9]);
+    }
+
+    #[test]
+    fn dollars_without_numbers() {
+        let expansion = Expansion::new("$ costs $$");
+        assert_eq!(expansion.text, "$ costs $$");
+        assert!(expansion.placeholders.is_empty());
+    }
+
+    #[test]
+    fn placeholders_follow_edits() {
+        // '\frac{$1}{$2}$0' inserted at 4
+        let mut placeholders = Placeholders::new(4, &Expansion::new(r"\frac{$1}{$2}$0"));
+        assert_eq!(placeholders.next(4), Some(10));
+        // text typed in the first placeholder
+        placeholders.insert_text(10, 3);
+        // text typed before the snippet
+        placeholders.insert_text(0, 2);
+        assert_eq!(placeholders.next(15), Some(17));
+        // the denominator is deleted, as well as the brace after it
+        placeholders.delete_text(16, 18);
+        assert_eq!(placeholders.next(16), Some(16));
+        assert_eq!(placeholders.next(16), None);
+    }
+
+    #[test]
+    fn placeholders_are_forgotten_outside_the_snippet() {
+        let expansion = Expansion::new(r"\sqrt{$1}$0");
+        let mut placeholders = Placeholders::new(2, &expansion);
+        assert_eq!(placeholders.next(2), Some(8));
+        assert_eq!(placeholders.next(0), None);
+        assert_eq!(placeholders.next(8), None);
+
+        let mut placeholders = Placeholders::new(2, &expansion);
+        // the snippet is deleted
+        placeholders.delete_text(0, 9);
+        placeholders.insert_text(0, 5);
+        assert_eq!(placeholders.next(5), None);
+    }
+
+    #[test]
+    fn snippet_names() {
+        assert_eq!(name_start("a frac", 6), Some(2));
+        assert_eq!(name_start("x+mat_2", 7), Some(2));
+        assert_eq!(name_start("é", 1), Some(0));
+        assert_eq!(name_start(r"\frac", 5), None);
+        assert_eq!(name_start("a ", 2), None);
+    }
+}
@@ -1,51 +1,72 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
-use gtk4::prelude::{ActionMapExt, EditableExtManual, DrawingAreaExtManual};
-use gtk4::prelude::{GtkApplicationExt, GtkWindowExt, EditableExt, WidgetExt, BoxExt};
+use gtk4::prelude::{ActionMapExt, EditableExtManual, DrawingAreaExtManual, EventControllerExt};
+use gtk4::prelude::{GtkApplicationExt, GtkWindowExt, EditableExt, WidgetExt, BoxExt, ListBoxRowExt};
 use gtk4::gio::SimpleAction;
+use gtk4::prelude::FileMonitorExt;
 use gtk4::glib::clone;
-use gtk4::{DrawingArea, glib, Statusbar, Entry};
+use gtk4::{DrawingArea, gdk, glib, Statusbar, Entry};
 use gtk4::{Application, ApplicationWindow};
 use rex::font::backend::ttf_parser::TtfMathFont;
 use rex::cairo::CairoBackend;
 
 
-use crate::desktop::cli::{EXAMPLE_FORMULA, UI_FONT_SIZE};
+use crate::desktop::cli::{read_styfiles, EXAMPLE_FORMULA, UI_FONT_SIZE};
 use crate::render::draw_formula;
-use crate::desktop::undo::{UndoStack, get_selection};
+use crate::desktop::undo::{UndoStack, get_selection, transaction};
+use crate::desktop::history::{self, HistoryEntry};
+use crate::desktop::snippets::{self, Placeholders, Snippets};
 use crate::desktop::app::{save_to_output, AppContext};
+use crate::desktop::watch::{watch_file, tex_snippet};
+use crate::error::AppError;
+use crate::macros::CustomCommands;
 
 
 
 
 struct Ui {
     window : ApplicationWindow, 
+    main_box : gtk4::Box,
     draw_area : DrawingArea, 
     text_field : Entry, 
     status_bar : Statusbar,
 }
 
+/// Number of formulas kept in the history file and shown in the history panel
+const HISTORY_LENGTH : usize = 200;
+/// Context of the status bar for errors of the style files, which stay under the errors of the formula until the files are fixed
+const STYFILE_ERROR_CONTEXT : u32 = 1;
+/// Size of the renders of formulas in the history panel
+const THUMBNAIL_SIZE : (i32, i32) = (120, 36);
+
 
 
 pub fn build_ui(app : &Application, font : TtfMathFont<'static>, app_context : AppContext) {
-    let AppContext { format, font_size, outfile, informula, metainfo, custom_cmd, .. } = app_context;
+    let AppContext { format, font_size, outfile, informula, metainfo, custom_cmd, styfiles, styfile_errors, follow, undo_depth, .. } = app_context;
     let format     = format.get();
     let metainfo   = metainfo.get();
     let font_size  = font_size.get();
     let font = Rc::new(font);
 
-    let Ui { window, draw_area, text_field, status_bar, } = construct_widgets(app, informula);
+    let following  = follow.borrow().is_some();
+    // a followed file is edited elsewhere: reloads are not edits which could be undone
+    let undo_stack = (!following).then(|| Rc::new(RefCell::new(UndoStack::restore(informula.borrow().as_str(), undo_depth.get()))));
+
+    let initial_formula = informula.borrow().clone();
+    let Ui { window, main_box, draw_area, text_field, status_bar, } = construct_widgets(app, informula);
 
 
 
 
-    let undo_stack = Rc::new(RefCell::new(UndoStack::new()));
-    setup_undo_actions(app, undo_stack.clone(), text_field.clone());
     let last_ok_string = Rc::new(RefCell::new(EXAMPLE_FORMULA.to_string()));
+    let styfile_broken = Rc::new(Cell::new(false));
+    let errors : Vec<String> = styfile_errors.borrow().iter().map(ToString::to_string).collect();
+    show_styfile_errors(&status_bar, &styfile_broken, &errors);
 
-    draw_area.set_draw_func(clone!(#[strong] font, #[strong] text_field, #[strong] last_ok_string, #[strong] status_bar, #[strong] custom_cmd, move |_area, context, width, height| {
+    draw_area.set_draw_func(clone!(#[strong] font, #[strong] text_field, #[strong] last_ok_string, #[strong] status_bar, #[strong] custom_cmd, #[strong] styfile_broken, move |_area, context, width, height| {
         let text = text_field.text();
         context.set_source_rgb(0.0, 0.0, 0.0);
 
@@ -66,7 +87,9 @@ pub fn build_ui(app : &Application, font : TtfMathFont<'static>, app_context : A
         match result {
             Ok(_)  => {
                 status_bar.pop(0);
-                status_bar.hide();
+                if !styfile_broken.get() {
+                    status_bar.hide();
+                }
                 let mut str_ref = last_ok_string.borrow_mut();
                 str_ref.clear();
                 str_ref.push_str(text.as_str());
@@ -94,29 +117,270 @@ pub fn build_ui(app : &Application, font : TtfMathFont<'static>, app_context : A
     text_field.connect_changed(clone!(#[weak] draw_area, move |_text_buffer| {
         draw_area.queue_draw()
     }));
-    text_field.delegate().unwrap().connect_insert_text(clone!(#[strong] undo_stack, move |entry, text, pt| {
-        let selection = get_selection(entry);
-        undo_stack.borrow_mut().insert_text(text, *pt, selection);
-    }));
-    text_field.delegate().unwrap().connect_delete_text(clone!(#[strong] undo_stack, move |entry, start_pos, end_pos| {
-        let deleted_text = entry.chars(start_pos, end_pos);
-        let selection = get_selection(entry);
-        undo_stack.borrow_mut().delete_text(deleted_text.as_str(), start_pos, end_pos, selection);
-    }));
-
 
-    window.connect_close_request(clone!(#[strong] text_field, #[strong] outfile, #[strong] font, #[strong] custom_cmd, move |_| {
+    if let Some(undo_stack) = &undo_stack {
+        setup_undo_actions(app, undo_stack.clone(), text_field.clone());
+        text_field.delegate().unwrap().connect_insert_text(clone!(#[strong] undo_stack, move |entry, text, pt| {
+            let selection = get_selection(entry);
+            undo_stack.borrow_mut().insert_text(text, *pt, selection);
+        }));
+        text_field.delegate().unwrap().connect_delete_text(clone!(#[strong] undo_stack, move |entry, start_pos, end_pos| {
+            let deleted_text = entry.chars(start_pos, end_pos);
+            let selection = get_selection(entry);
+            undo_stack.borrow_mut().delete_text(deleted_text.as_str(), start_pos, end_pos, selection);
+        }));
+
+        setup_snippets(&text_field, undo_stack.clone());
+        let history_panel = build_history_panel(font.clone(), custom_cmd.clone(), undo_stack.clone(), text_field.clone());
+        main_box.insert_child_after(&history_panel, Some(&draw_area));
+    }
+
+    window.connect_close_request(clone!(#[strong] text_field, #[strong] outfile, #[strong] font, #[strong] custom_cmd, #[strong] undo_stack, #[strong] last_ok_string, move |_| {
         let text = text_field.text();
+        // the edits of a followed file are not made in the app
+        if let Some(undo_stack) = &undo_stack {
+            if let Err(error) = undo_stack.borrow().save(&text) {
+                eprintln!("Could not save undo history: {}", error);
+            }
+        }
         // TODO: error handling
         // Can't really see how to set an exit status code once the app is running
         save_to_output(&text, outfile.borrow().deref(), format, font.clone(), font_size, metainfo, custom_cmd.borrow().deref()).unwrap();
+
+        // only formulas of one's own, which were edited until they render, are worth keeping
+        let edited = !following && text != initial_formula && *last_ok_string.borrow() == text;
+        if edited {
+            if let Err(error) = history::append(&HistoryEntry::now(&text, format), HISTORY_LENGTH) {
+                eprintln!("Could not save formula to history: {}", error);
+            }
+        }
         glib::signal::Propagation::Proceed
     }));
 
+    if let Some(path) = follow.borrow().clone() {
+        follow_file(&window, &text_field, &status_bar, path);
+    }
+    if !styfiles.borrow().is_empty() {
+        reload_styfiles_on_change(&window, &draw_area, &status_bar, &styfile_broken, custom_cmd.clone(), styfiles.borrow().clone());
+    }
+
     window.show();
     
 }
 
+/// Expands the snippet whose name is before the cursor when Tab is pressed ; Tab then jumps to the placeholders of the snippet.
+fn setup_snippets(text_field : &Entry, undo_stack : Rc<RefCell<UndoStack>>) {
+    let snippets = match Snippets::load() {
+        Ok(snippets) => snippets,
+        Err(error)   => {
+            eprintln!("Could not load snippets: {}", error);
+            return;
+        },
+    };
+    let placeholders = Rc::new(RefCell::new(Placeholders::default()));
+
+    let delegate = text_field.delegate().unwrap();
+    delegate.connect_insert_text(clone!(#[strong] placeholders, move |_, text, point| {
+        placeholders.borrow_mut().insert_text(*point, text.chars().count() as i32);
+    }));
+    delegate.connect_delete_text(clone!(#[strong] placeholders, move |_, start, end| {
+        placeholders.borrow_mut().delete_text(start, end);
+    }));
+
+    let key_controller = gtk4::EventControllerKey::new();
+    // the entry would otherwise move the focus to the next widget
+    key_controller.set_propagation_phase(gtk4::PropagationPhase::Capture);
+    key_controller.connect_key_pressed(clone!(#[weak] text_field, #[upgrade_or] glib::Propagation::Proceed, move |_, key, _, modifiers| {
+        if key != gdk::Key::Tab || modifiers.intersects(gdk::ModifierType::SHIFT_MASK | gdk::ModifierType::CONTROL_MASK | gdk::ModifierType::ALT_MASK) {
+            return glib::Propagation::Proceed;
+        }
+
+        let next_placeholder = placeholders.borrow_mut().next(text_field.position());
+        if let Some(position) = next_placeholder {
+            text_field.set_position(position);
+            return glib::Propagation::Stop;
+        }
+
+        let cursor = text_field.position();
+        let text = text_field.text();
+        let Some(start) = snippets::name_start(&text, cursor as usize) else { return glib::Propagation::Proceed; };
+        let name : String = text.chars().skip(start).take(cursor as usize - start).collect();
+        let Some(expansion) = snippets.expand(&name) else { return glib::Propagation::Proceed; };
+
+        let start = start as i32;
+        let position = transaction(&undo_stack, || {
+            text_field.delete_text(start, cursor);
+            let mut position = start;
+            text_field.insert_text(&expansion.text, &mut position);
+            position
+        });
+
+        let mut new_placeholders = Placeholders::new(start, &expansion);
+        text_field.set_position(new_placeholders.next(position).unwrap_or(position));
+        *placeholders.borrow_mut() = new_placeholders;
+        glib::Propagation::Stop
+    }));
+    text_field.add_controller(key_controller);
+}
+
+/// Panel listing the formulas of previous sessions, with a search field ; picking a formula replaces the text of the formula field.
+fn build_history_panel(font : Rc<TtfMathFont<'static>>, custom_cmd : Rc<RefCell<CustomCommands>>, undo_stack : Rc<RefCell<UndoStack>>, text_field : Entry) -> gtk4::Expander {
+    let entries = history::load(HISTORY_LENGTH);
+
+    let list = gtk4::ListBox::builder()
+        .selection_mode(gtk4::SelectionMode::None)
+        .build()
+    ;
+    for entry in &entries {
+        list.append(&history_row(entry, font.clone(), custom_cmd.clone()));
+    }
+
+    let search_field = gtk4::SearchEntry::builder()
+        .placeholder_text("Search history")
+        .build()
+    ;
+    let formulas : Rc<Vec<String>> = Rc::new(entries.into_iter().map(|entry| entry.formula).collect());
+    list.set_filter_func(clone!(#[weak] search_field, #[strong] formulas, #[upgrade_or] true, move |row| {
+        let query = search_field.text();
+        formulas.get(row.index() as usize).map(|formula| formula.contains(query.as_str())).unwrap_or(true)
+    }));
+    search_field.connect_search_changed(clone!(#[weak] list, move |_| list.invalidate_filter()));
+
+    list.connect_row_activated(clone!(#[strong] formulas, move |_, row| {
+        let Some(formula) = formulas.get(row.index() as usize) else { return; };
+        // replacing the text deletes the old text and inserts the new one ; both are undone at once
+        transaction(&undo_stack, || text_field.set_text(formula));
+    }));
+
+    let scrolled_window = gtk4::ScrolledWindow::builder()
+        .min_content_height(150)
+        .child(&list)
+        .build()
+    ;
+    let vbox = gtk4::Box::builder()
+        .orientation(gtk4::Orientation::Vertical)
+        .build()
+    ;
+    vbox.append(&search_field);
+    vbox.append(&scrolled_window);
+
+    gtk4::Expander::builder()
+        .label("History")
+        .child(&vbox)
+        .build()
+}
+
+fn history_row(entry : &HistoryEntry, font : Rc<TtfMathFont<'static>>, custom_cmd : Rc<RefCell<CustomCommands>>) -> gtk4::Box {
+    let (thumbnail_width, thumbnail_height) = THUMBNAIL_SIZE;
+    let thumbnail = DrawingArea::builder()
+        .content_width(thumbnail_width)
+        .content_height(thumbnail_height)
+        .build()
+    ;
+    let formula = entry.formula.clone();
+    thumbnail.set_draw_func(move |_area, context, width, height| {
+        context.set_source_rgb(0.0, 0.0, 0.0);
+        let mut backend = CairoBackend::new(context.clone());
+        // formulas which no longer parse, e.g. because a custom command was removed, are left blank
+        draw_formula(
+            &formula,
+            &mut backend,
+            font.as_ref(),
+            UI_FONT_SIZE,
+            Some((width as f64, height as f64)),
+            custom_cmd.borrow().deref()
+        ).unwrap_or(());
+    });
+
+    let label = gtk4::Label::builder()
+        .label(entry.formula.as_str())
+        .ellipsize(gtk4::pango::EllipsizeMode::End)
+        .xalign(0.)
+        .hexpand(true)
+        .build()
+    ;
+
+    let row = gtk4::Box::builder()
+        .orientation(gtk4::Orientation::Horizontal)
+        .spacing(6)
+        .build()
+    ;
+    row.append(&thumbnail);
+    row.append(&label);
+    let date = glib::DateTime::from_unix_local(entry.timestamp as i64)
+        .and_then(|date| date.format("%Y-%m-%d %H:%M"))
+        .map(|date| date.to_string())
+        .unwrap_or_default();
+    row.set_tooltip_text(Some(&format!("{} ({})", date, entry.format)));
+    row
+}
+
+/// Turns the window into a read-only preview of the formula in 'path', which is updated whenever the file changes.
+/// The file is read as a '.tex' snippet.
+fn follow_file(window : &ApplicationWindow, text_field : &Entry, status_bar : &Statusbar, path : PathBuf) {
+    text_field.set_editable(false);
+    window.set_title(Some(&format!("Math Preview - {}", path.display())));
+
+    // the formula is drawn by the draw function of the drawing area, which reports parse errors
+    let reload = clone!(#[weak] text_field, #[weak] status_bar, #[strong] path, move || {
+        match std::fs::read_to_string(&path) {
+            Ok(source) => text_field.set_text(&tex_snippet(&source)),
+            Err(error) => show_error(&status_bar, &format!("{:?}: {}", path, AppError::from(error))),
+        }
+    });
+    reload();
+
+    watch_while_open(window, status_bar, &path, reload);
+}
+
+/// Parses the style files again whenever one of them changes, so that custom commands can be edited while the app runs.
+/// If a file is broken, the error is shown in the status bar and the previous commands are kept.
+fn reload_styfiles_on_change(window : &ApplicationWindow, draw_area : &DrawingArea, status_bar : &Statusbar, styfile_broken : &Rc<Cell<bool>>, custom_cmd : Rc<RefCell<CustomCommands>>, paths : Vec<PathBuf>) {
+    let paths = Rc::new(paths);
+    for path in paths.iter() {
+        let reload = clone!(#[weak] draw_area, #[weak] status_bar, #[strong] styfile_broken, #[strong] paths, #[strong] custom_cmd, move || {
+            match read_styfiles(&paths) {
+                Ok(new_custom_cmd) => {
+                    *custom_cmd.borrow_mut() = new_custom_cmd;
+                    show_styfile_errors(&status_bar, &styfile_broken, &[]);
+                    draw_area.queue_draw();
+                },
+                // the previous commands stay in use: the error must stay visible until the files are fixed
+                Err(error) => show_styfile_errors(&status_bar, &styfile_broken, &[error.to_string()]),
+            }
+        });
+        watch_while_open(window, status_bar, path, reload);
+    }
+}
+
+/// Calls 'on_change' whenever the file changes, for as long as the window is open
+fn watch_while_open<F : Fn() + 'static>(window : &ApplicationWindow, status_bar : &Statusbar, path : &Path, on_change : F) {
+    match watch_file(path, on_change) {
+        Ok(monitor) => { window.connect_destroy(move |_| { monitor.cancel(); }); },
+        Err(error)  => show_error(status_bar, &format!("{:?}: {}", path, error)),
+    }
+}
+
+/// Shows the errors of the style files which could not be loaded, or removes them if there are none
+fn show_styfile_errors(status_bar : &Statusbar, styfile_broken : &Cell<bool>, errors : &[String]) {
+    status_bar.pop(STYFILE_ERROR_CONTEXT);
+    styfile_broken.set(!errors.is_empty());
+    if errors.is_empty() {
+        return;
+    }
+    let message = errors.join(" ; ");
+    eprintln!("{}", message);
+    status_bar.push(STYFILE_ERROR_CONTEXT, &message);
+    status_bar.show();
+}
+
+fn show_error(status_bar : &Statusbar, message : &str) {
+    status_bar.pop(0);
+    status_bar.show();
+    eprintln!("{}", message);
+    status_bar.push(0, message);
+}
+
 fn construct_widgets(app: &Application, informula: Rc<RefCell<String>>) -> Ui {
     let window = ApplicationWindow::builder()
         .application(app)
@@ -170,7 +434,7 @@ fn construct_widgets(app: &Application, informula: Rc<RefCell<String>>) -> Ui {
     vbox.append(&draw_area);
     vbox.append(&status_bar);
     window.set_child(Some(&vbox));
-    Ui { window, draw_area, text_field, status_bar }
+    Ui { window, main_box: vbox, draw_area, text_field, status_bar }
 }
 
 fn setup_undo_actions(app: &Application, undo_stack : Rc<RefCell<UndoStack>>, text_field : Entry) {
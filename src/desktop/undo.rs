@@ -1,6 +1,13 @@
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
 use gtk4::{EntryBuffer, Entry, prelude::EntryBufferExtManual, prelude::{EditableExt, EntryExt}, Editable};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+use crate::error::AppResult;
+
+#[derive(Debug, Serialize, Deserialize)]
 enum EditEvent {
     InsertText {
         content : String,
@@ -14,8 +21,25 @@ enum EditEvent {
 }
 
 
+/// Text which edit events are applied to ; positions and lengths are in characters
+trait TextBuffer {
+    fn insert(&self, position : u16, text : &str);
+    fn delete(&self, position : u16, length : u16);
+}
+
+impl TextBuffer for EntryBuffer {
+    fn insert(&self, position : u16, text : &str) {
+        self.insert_text(position, text);
+    }
+
+    fn delete(&self, position : u16, length : u16) {
+        self.delete_text(position, Some(length));
+    }
+}
+
+
 impl EditEvent {
-    fn apply_change(&self, buffer : EntryBuffer) -> Option<()> {
+    fn apply_change(&self, buffer : &impl TextBuffer) -> Option<()> {
         match self {
             EditEvent::InsertText { content, point } 
             => Self::insert_text(buffer, *point, content.as_str()),
@@ -24,7 +48,7 @@ impl EditEvent {
         }
     }    
 
-    fn unapply_change(&self, buffer : EntryBuffer) -> Option<()> {
+    fn unapply_change(&self, buffer : &impl TextBuffer) -> Option<()> {
         match self {
             EditEvent::InsertText { content, point } 
             => Self::delete_text(buffer, *point, *point + (content.chars().count() as i32)),
@@ -33,16 +57,59 @@ impl EditEvent {
         }
     }
 
-    fn delete_text(buffer : EntryBuffer, start : i32, end : i32,) -> Option<()> {
-        buffer.delete_text(
+    /// Single characters typed or deleted may be coalesced ; longer edits, e.g. pasted text, can't
+    fn is_keystroke(&self) -> bool {
+        let content = match self {
+            EditEvent::InsertText { content, .. } |
+            EditEvent::DeleteText { content, .. } => content,
+        };
+        content.chars().count() == 1
+    }
+
+    /// Adds a keystroke to this event, if it continues it: typing right after the inserted text,
+    /// deleting right before (backspace) or at (delete) the deleted text, without starting a new word.
+    /// Returns whether the keystroke was added.
+    fn coalesce(&mut self, keystroke : &EditEvent) -> bool {
+        match (self, keystroke) {
+            (EditEvent::InsertText { content, point }, EditEvent::InsertText { content: new, point: new_point }) => {
+                let adjacent = *new_point == *point + content.chars().count() as i32;
+                if !adjacent || starts_word(content.chars().last(), new) {
+                    return false;
+                }
+                content.push_str(new);
+                true
+            },
+            (EditEvent::DeleteText { content, start, end }, EditEvent::DeleteText { content: new, start: new_start, end: new_end }) => {
+                // backspace
+                if *new_end == *start && !starts_word(new.chars().last(), content) {
+                    content.insert_str(0, new);
+                    *start = *new_start;
+                    true
+                }
+                // delete
+                else if *new_start == *start && !starts_word(content.chars().last(), new) {
+                    content.push_str(new);
+                    *end += new_end - new_start;
+                    true
+                }
+                else {
+                    false
+                }
+            },
+            _ => false,
+        }
+    }
+
+    fn delete_text(buffer : &impl TextBuffer, start : i32, end : i32,) -> Option<()> {
+        buffer.delete(
             start.try_into().ok()?, 
-            Some((end - start).try_into().ok()?)
+            (end - start).try_into().ok()?
         );
         Some(())
     }
 
-    fn insert_text(buffer : EntryBuffer, start : i32, text : &str) -> Option<()> {
-        buffer.insert_text(
+    fn insert_text(buffer : &impl TextBuffer, start : i32, text : &str) -> Option<()> {
+        buffer.insert(
             start.try_into().ok()?, 
             text
         );
@@ -50,23 +117,98 @@ impl EditEvent {
     }
 } 
 
-#[derive(Debug)]
+/// Edit events undone and redone together, e.g. the deletion and the insertion which replace the whole text
+#[derive(Debug, Serialize, Deserialize)]
 struct Change {
     original_selection : (i32, i32),
-    event : EditEvent,
+    events : Vec<EditEvent>,
 }
 
+/// Keystrokes closer in time than this may be undone together
+const COALESCING_PAUSE : Duration = Duration::from_millis(1000);
+
+/// Number of changes which can be undone, unless specified otherwise
+pub const DEFAULT_UNDO_DEPTH : usize = 100;
+/// Number of formulas whose undo history is kept on disk
+const SAVED_HISTORIES : usize = 500;
+
 #[derive(Debug)]
 pub struct UndoStack {
-    past   : Vec<Change>,
-    future : Vec<Change>,
+    past     : Vec<Change>,
+    future   : Vec<Change>,
+    /// Maximal number of changes in 'past' ; the oldest changes are forgotten
+    max_depth : usize,
+    /// Number of nested transactions currently open
+    transaction_depth : usize,
+    /// Whether the current transaction already has a change, which further events are added to
+    transaction_open  : bool,
+    /// Time of the last keystroke, if the next keystroke may be added to the change of the last one
+    last_keystroke    : Option<Instant>,
 }
 
 impl UndoStack {
-    pub fn new() -> Self { 
+    pub fn new(max_depth : usize) -> Self { 
         let past   = Vec::with_capacity(20);
         let future = Vec::with_capacity(5);
-        Self { past, future, }
+        Self { past, future, max_depth, transaction_depth: 0, transaction_open: false, last_keystroke: None, }
+    }
+
+    /// Restores the undo history saved when the app was last closed on the same formula, e.g. when a plugin edits a formula again.
+    /// Returns an empty history if there is none.
+    pub fn restore(formula : &str, max_depth : usize) -> Self {
+        let mut undo_stack = Self::new(max_depth);
+        let saved_history = std::fs::read_to_string(saved_history_path(formula)).ok()
+            .and_then(|json| serde_json::from_str::<SavedHistory>(&json).ok());
+        // the file name is a hash of the formula, which may collide
+        if let Some(SavedHistory { formula: saved_formula, past, future }) = saved_history {
+            if saved_formula == formula {
+                undo_stack.past   = past;
+                undo_stack.future = future;
+                undo_stack.forget_oldest_changes();
+            }
+        }
+        undo_stack
+    }
+
+    /// Saves the undo history, to be restored when the app is opened on 'formula', the final text of the formula field
+    pub fn save(&self, formula : &str) -> AppResult<()> {
+        let path = saved_history_path(formula);
+        if self.past.is_empty() && self.future.is_empty() {
+            return match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+
+        let saved_history = SavedHistoryRef { formula, past: &self.past, future: &self.future };
+        let json = serde_json::to_string(&saved_history).map_err(std::io::Error::from)?;
+        let folder = saved_histories_dir();
+        std::fs::create_dir_all(&folder)?;
+        std::fs::write(&path, json)?;
+        forget_oldest_histories(&folder)?;
+        Ok(())
+    }
+
+    fn forget_oldest_changes(&mut self) {
+        if self.past.len() > self.max_depth {
+            let excess = self.past.len() - self.max_depth;
+            self.past.drain(.. excess);
+        }
+        self.future.truncate(self.max_depth);
+    }
+
+    /// Until the matching 'end_transaction', all edits are recorded as a single change.
+    /// Transactions can be nested ; the outermost one makes the change.
+    pub fn begin_transaction(&mut self) {
+        if self.transaction_depth == 0 {
+            self.transaction_open = false;
+        }
+        self.transaction_depth += 1;
+        self.last_keystroke = None;
+    }
+
+    pub fn end_transaction(&mut self) {
+        self.transaction_depth = self.transaction_depth.saturating_sub(1);
     }
 
     fn set_selection(entry : Entry, selection : (i32, i32)) {
@@ -74,61 +216,159 @@ impl UndoStack {
     }
 
     pub fn undo(&mut self, entry : Entry) -> bool {
-        if let Some(mut change) = self.past.pop() {
-            let original_selection = change.original_selection;
-            change.original_selection = get_selection(&entry.delegate().unwrap());
-
-            change.event.unapply_change(entry.buffer());
-            Self::set_selection(entry, original_selection);
-            self.future.push(change);
-            true
+        let selection = get_selection(&entry.delegate().unwrap());
+        match self.undo_in(&entry.buffer(), selection) {
+            Some(original_selection) => { Self::set_selection(entry, original_selection); true },
+            None => false,
         }
-        else {
-            false
+    }
+
+    /// Undoes the last change in 'buffer' ; returns the selection before the change
+    fn undo_in(&mut self, buffer : &impl TextBuffer, selection : (i32, i32)) -> Option<(i32, i32)> {
+        self.last_keystroke = None;
+        let mut change = self.past.pop()?;
+        let original_selection = change.original_selection;
+        change.original_selection = selection;
+
+        for event in change.events.iter().rev() {
+            event.unapply_change(buffer);
         }
+        self.future.push(change);
+        Some(original_selection)
     }
 
 
     pub fn redo(&mut self, entry : Entry) -> bool {
-        if let Some(mut change) = self.future.pop() {
-            let original_selection = change.original_selection;
-            change.original_selection = entry.selection_bounds().unwrap_or_else(||{
-                let selection = entry.selection_bound();
-                (selection, selection)
-            });
-
-            change.event.apply_change(entry.buffer());
-            Self::set_selection(entry, original_selection);
-            self.past.push(change);
-            true
+        let selection = entry.selection_bounds().unwrap_or_else(||{
+            let selection = entry.selection_bound();
+            (selection, selection)
+        });
+        match self.redo_in(&entry.buffer(), selection) {
+            Some(original_selection) => { Self::set_selection(entry, original_selection); true },
+            None => false,
         }
-        else {
-            false
+    }
+
+    /// Redoes the last undone change in 'buffer' ; returns the selection after the change
+    fn redo_in(&mut self, buffer : &impl TextBuffer, selection : (i32, i32)) -> Option<(i32, i32)> {
+        self.last_keystroke = None;
+        let mut change = self.future.pop()?;
+        let original_selection = change.original_selection;
+        change.original_selection = selection;
+
+        for event in &change.events {
+            event.apply_change(buffer);
         }
+        self.past.push(change);
+        Some(original_selection)
     }
 
     pub fn insert_text(&mut self, new : &str, insertion_pt : i32, selection : (i32, i32)) {
-        self.future.clear();
-        self.past.push(Change { 
-            event: EditEvent::InsertText {
-                content: new.to_string(),
-                point:   insertion_pt,
-            },
-            original_selection: selection, 
-        });
+        self.push_event(EditEvent::InsertText {
+            content: new.to_string(),
+            point:   insertion_pt,
+        }, selection);
     }
 
+    /// Records the deletion of 'deleted_chunk' ; a negative 'end' (as GTK passes when the whole text is replaced) means the end of the text
     pub fn delete_text(&mut self, deleted_chunk : &str, start : i32, end : i32, selection : (i32, i32)) {
+        let end = if end < 0 { start + deleted_chunk.chars().count() as i32 } else { end };
+        self.push_event(EditEvent::DeleteText { 
+            content: deleted_chunk.to_string(),
+            start, end,
+        }, selection);
+    }
+
+    fn push_event(&mut self, event : EditEvent, selection : (i32, i32)) {
         self.future.clear();
-        self.past.push(Change { 
-            event: EditEvent::DeleteText { 
-                content: deleted_chunk.to_string(),
-                start, end,
-            },
-            original_selection: selection, 
-        });
+        if self.transaction_depth > 0 {
+            match self.past.last_mut() {
+                Some(change) if self.transaction_open => change.events.push(event),
+                _ => {
+                    self.past.push(Change { events: vec![event], original_selection: selection, });
+                    self.forget_oldest_changes();
+                    self.transaction_open = true;
+                },
+            }
+            return;
+        }
+
+        let now = Instant::now();
+        let recent = self.last_keystroke.map(|last_keystroke| now.duration_since(last_keystroke) < COALESCING_PAUSE).unwrap_or(false);
+        let is_keystroke = event.is_keystroke();
+        let coalesced = match self.past.last_mut() {
+            Some(Change { events, .. }) if recent && is_keystroke && events.len() == 1 => events[0].coalesce(&event),
+            _ => false,
+        };
+        if !coalesced {
+            self.past.push(Change { events: vec![event], original_selection: selection, });
+            self.forget_oldest_changes();
+        }
+        self.last_keystroke = if is_keystroke { Some(now) } else { None };
+    }
+
+}
+
+#[derive(Deserialize)]
+struct SavedHistory {
+    formula : String,
+    past    : Vec<Change>,
+    future  : Vec<Change>,
+}
+
+#[derive(Serialize)]
+struct SavedHistoryRef<'a> {
+    formula : &'a str,
+    past    : &'a [Change],
+    future  : &'a [Change],
+}
+
+fn saved_histories_dir() -> PathBuf {
+    gtk4::glib::user_data_dir().join("maths_preview").join("undo")
+}
+
+fn saved_history_path(formula : &str) -> PathBuf {
+    saved_histories_dir().join(format!("{:016x}.json", fnv1a(formula.as_bytes())))
+}
+
+/// Removes the least recently saved histories, so that at most 'SAVED_HISTORIES' remain
+fn forget_oldest_histories(folder : &std::path::Path) -> AppResult<()> {
+    let mut histories : Vec<_> = std::fs::read_dir(folder)?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            Some((entry.metadata().ok()?.modified().ok()?, entry.path()))
+        })
+        .collect();
+    if histories.len() <= SAVED_HISTORIES {
+        return Ok(());
     }
+    histories.sort();
+    for (_, path) in &histories[.. histories.len() - SAVED_HISTORIES] {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
 
+/// 64-bit FNV-1a hash ; unlike 'DefaultHasher', it is stable across versions of Rust
+fn fnv1a(bytes : &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3))
+}
+
+/// Runs 'edit' as a single transaction of the undo stack, e.g. to replace the whole text in one undo step.
+/// The undo stack is not borrowed while 'edit' runs, since edits call back into it.
+pub fn transaction<R, F : FnOnce() -> R>(undo_stack : &RefCell<UndoStack>, edit : F) -> R {
+    undo_stack.borrow_mut().begin_transaction();
+    let result = edit();
+    undo_stack.borrow_mut().end_transaction();
+    result
+}
+
+/// Whether 'text' starts a new word after 'previous' ; typing a new word starts a new undo step.
+/// In formulas, commands (e.g. '\alpha') start words as well.
+fn starts_word(previous : Option<char>, text : &str) -> bool {
+    let Some(next) = text.chars().next() else { return false; };
+    let previous_is_space = previous.map(char::is_whitespace).unwrap_or(true);
+    (next.is_whitespace() && !previous_is_space) || next == '\\'
 }
 
 pub fn get_selection(entry: &Editable) -> (i32, i32) {
@@ -142,3 +382,128 @@ pub fn get_selection(entry: &Editable) -> (i32, i32) {
 
 
 
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl TextBuffer for RefCell<String> {
+        fn insert(&self, position : u16, text : &str) {
+            let mut string = self.borrow_mut();
+            let index = byte_index(&string, position);
+            string.insert_str(index, text);
+        }
+
+        fn delete(&self, position : u16, length : u16) {
+            let mut string = self.borrow_mut();
+            let start = byte_index(&string, position);
+            let end   = byte_index(&string, position + length);
+            string.replace_range(start .. end, "");
+        }
+    }
+
+    fn byte_index(string : &str, position : u16) -> usize {
+        string.char_indices().nth(position.into()).map(|(index, _)| index).unwrap_or(string.len())
+    }
+
+    /// Edits 'buffer' and records the edits, as the signal handlers of the formula field do
+    fn insert(undo_stack : &mut UndoStack, buffer : &RefCell<String>, position : i32, text : &str) {
+        buffer.insert(position as u16, text);
+        undo_stack.insert_text(text, position, (position, position));
+    }
+
+    fn delete(undo_stack : &mut UndoStack, buffer : &RefCell<String>, start : i32, end : i32) {
+        let content : String = {
+            let string = buffer.borrow();
+            let end = if end < 0 { string.chars().count() } else { end as usize };
+            string.chars().take(end).skip(start as usize).collect()
+        };
+        buffer.delete(start as u16, content.chars().count() as u16);
+        undo_stack.delete_text(&content, start, end, (start, start));
+    }
+
+    #[test]
+    fn whole_text_replacement_round_trip() {
+        let mut undo_stack = UndoStack::new(DEFAULT_UNDO_DEPTH);
+        let buffer = RefCell::new("x^2 é".to_string());
+        // 'set_text' deletes up to -1, i.e. to the end of the text
+        undo_stack.begin_transaction();
+        delete(&mut undo_stack, &buffer, 0, -1);
+        insert(&mut undo_stack, &buffer, 0, r"\frac{1}{2}");
+        undo_stack.end_transaction();
+        assert_eq!(*buffer.borrow(), r"\frac{1}{2}");
+
+        for _ in 0 .. 2 {
+            assert!(undo_stack.undo_in(&buffer, (0, 0)).is_some());
+            assert_eq!(*buffer.borrow(), "x^2 é");
+            assert!(undo_stack.redo_in(&buffer, (0, 0)).is_some());
+            assert_eq!(*buffer.borrow(), r"\frac{1}{2}");
+        }
+        assert!(undo_stack.redo_in(&buffer, (0, 0)).is_none());
+    }
+
+    #[test]
+    fn keystrokes_are_coalesced_by_word() {
+        let mut undo_stack = UndoStack::new(DEFAULT_UNDO_DEPTH);
+        let buffer = RefCell::new(String::new());
+        for (position, character) in r"ab\c".chars().enumerate() {
+            insert(&mut undo_stack, &buffer, position as i32, &character.to_string());
+        }
+        // backspace twice
+        delete(&mut undo_stack, &buffer, 3, 4);
+        delete(&mut undo_stack, &buffer, 2, 3);
+        assert_eq!(*buffer.borrow(), "ab");
+
+        undo_stack.undo_in(&buffer, (0, 0));
+        assert_eq!(*buffer.borrow(), r"ab\c");
+        undo_stack.undo_in(&buffer, (0, 0));
+        assert_eq!(*buffer.borrow(), "ab");
+        undo_stack.undo_in(&buffer, (0, 0));
+        assert_eq!(*buffer.borrow(), "");
+        assert!(undo_stack.undo_in(&buffer, (0, 0)).is_none());
+    }
+
+    #[test]
+    fn nested_transactions_make_one_change() {
+        let mut undo_stack = UndoStack::new(DEFAULT_UNDO_DEPTH);
+        let buffer = RefCell::new(String::new());
+        undo_stack.begin_transaction();
+        insert(&mut undo_stack, &buffer, 0, "ab");
+        undo_stack.begin_transaction();
+        insert(&mut undo_stack, &buffer, 2, "cd");
+        undo_stack.end_transaction();
+        insert(&mut undo_stack, &buffer, 0, "e");
+        undo_stack.end_transaction();
+        insert(&mut undo_stack, &buffer, 5, "fg");
+
+        undo_stack.undo_in(&buffer, (0, 0));
+        assert_eq!(*buffer.borrow(), "eabcd");
+        undo_stack.undo_in(&buffer, (0, 0));
+        assert_eq!(*buffer.borrow(), "");
+    }
+
+    #[test]
+    fn oldest_changes_are_forgotten() {
+        let mut undo_stack = UndoStack::new(2);
+        let buffer = RefCell::new(String::new());
+        for text in ["ab", "cd", "ef"] {
+            let end = buffer.borrow().len() as i32;
+            insert(&mut undo_stack, &buffer, end, text);
+        }
+        while undo_stack.undo_in(&buffer, (0, 0)).is_some() {}
+        assert_eq!(*buffer.borrow(), "ab");
+    }
+
+    #[test]
+    fn selection_is_restored() {
+        let mut undo_stack = UndoStack::new(DEFAULT_UNDO_DEPTH);
+        let buffer = RefCell::new("abc".to_string());
+        buffer.delete(0, 2);
+        undo_stack.delete_text("ab", 0, 2, (0, 2));
+        assert_eq!(undo_stack.undo_in(&buffer, (0, 0)), Some((0, 2)));
+        assert_eq!(undo_stack.redo_in(&buffer, (1, 3)), Some((0, 0)));
+        assert_eq!(undo_stack.undo_in(&buffer, (0, 0)), Some((1, 3)));
+    }
+}
@@ -0,0 +1,261 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use gtk4::gio::{self, Cancellable, FileMonitor, FileMonitorEvent, FileMonitorFlags};
+use gtk4::glib;
+use gtk4::prelude::{FileExt, FileMonitorExt};
+
+use crate::error::AppResult;
+use crate::desktop::app::{AppContext, load_font};
+use crate::desktop::cli::{self, Output, DEFAULT_FONT};
+use crate::render::render_svg;
+use crate::svg::SvgContext;
+
+
+
+/// Calls 'on_change' whenever the file at 'path' is modified, or replaced (as editors which save to a temporary file do).
+/// The file is only watched as long as the returned monitor is alive.
+pub(crate) fn watch_file<F : Fn() + 'static>(path : &Path, on_change : F) -> AppResult<FileMonitor> {
+    let monitor = gio::File::for_path(path)
+        .monitor_file(FileMonitorFlags::NONE, None::<&Cancellable>)
+        .map_err(std::io::Error::other)?;
+    monitor.connect_changed(move |_monitor, _file, _other_file, event| {
+        // 'Changed' is emitted many times while a file is being written ; 'ChangesDoneHint' once it is complete
+        if matches!(event, FileMonitorEvent::ChangesDoneHint | FileMonitorEvent::Created) {
+            on_change();
+        }
+    });
+    Ok(monitor)
+}
+
+
+/// Re-renders the formulas of a file whenever the file, the style file or the maths font changes.
+/// Errors are reported on stderr ; the last font and custom commands which loaded successfully are kept.
+pub(crate) struct Watcher {
+    input       : PathBuf,
+    font_path   : Option<(PathBuf, Option<u32>)>,
+    styfiles    : Vec<PathBuf>,
+    app_context : AppContext,
+    // the font is parsed anew for every render, so that changing the font file does not leak the previous one
+    font_bytes  : RefCell<Cow<'static, [u8]>>,
+}
+
+impl Watcher {
+    pub fn new(input : PathBuf, font_path : Option<(PathBuf, Option<u32>)>, styfiles : Vec<PathBuf>, app_context : AppContext) -> Self {
+        Self {
+            input,
+            font_path,
+            styfiles,
+            app_context,
+            font_bytes: RefCell::new(Cow::Borrowed(DEFAULT_FONT)),
+        }
+    }
+
+    /// Renders the formulas once, then every time one of the files changes, until the program is interrupted.
+    pub fn run(self) -> AppResult<()> {
+        let watcher = Rc::new(self);
+        watcher.reload_font();
+        watcher.reload_styfiles();
+        watcher.render();
+
+        let mut monitors = vec![watch_file(&watcher.input, {
+            let watcher = watcher.clone();
+            move || watcher.render()
+        })?];
+        for styfile in &watcher.styfiles {
+            monitors.push(watch_file(styfile, {
+                let watcher = watcher.clone();
+                move || { watcher.reload_styfiles(); watcher.render() }
+            })?);
+        }
+        if let Some((font_path, _)) = &watcher.font_path {
+            monitors.push(watch_file(font_path, {
+                let watcher = watcher.clone();
+                move || { watcher.reload_font(); watcher.render() }
+            })?);
+        }
+
+        eprintln!("Watching {:?} ; press Ctrl+C to stop", watcher.input);
+        glib::MainLoop::new(None, false).run();
+        drop(monitors);
+        Ok(())
+    }
+
+    fn reload_font(&self) {
+        let Some((path, _)) = &self.font_path else { return; };
+        match cli::read_font(path) {
+            Ok(font_bytes) => *self.font_bytes.borrow_mut() = Cow::Owned(font_bytes),
+            Err(e)         => eprintln!("{:?}: {}", path, e),
+        }
+    }
+
+    fn reload_styfiles(&self) {
+        if self.styfiles.is_empty() { return; }
+        match cli::read_styfiles(&self.styfiles) {
+            Ok(custom_cmd) => *self.app_context.custom_cmd.borrow_mut() = custom_cmd,
+            Err(e)         => eprintln!("{}", e),
+        }
+    }
+
+    fn render(&self) {
+        if let Err(e) = self.try_render() {
+            eprintln!("{}", e);
+        }
+    }
+
+    fn try_render(&self) -> AppResult<()> {
+        let source = std::fs::read_to_string(&self.input)?;
+        let is_tex = self.input.extension().map(|extension| extension == "tex").unwrap_or(false);
+        let formulas = if is_tex { vec![(None, tex_snippet(&source))] } else { formula_lines(&source) };
+
+        let font_bytes = self.font_bytes.borrow();
+        // '--faceindex' takes precedence over the index in the path, as in 'cli::handle_options'
+        let face_index = self.app_context.face_index.get().or(self.font_path.as_ref().and_then(|(_, index)| *index));
+        let font = load_font(&font_bytes, face_index)?;
+        let glyph_as_text = match self.app_context.format.get() {
+            cli::Format::Svg { glyph_as_text } => glyph_as_text,
+            cli::Format::Tex => false,
+        };
+
+        let outfile = self.app_context.outfile.borrow();
+        for (index, (line, formula)) in formulas.iter().enumerate() {
+            let rendered = render_svg(
+                formula,
+                None,
+                &font,
+                self.app_context.font_size.get(),
+                &self.app_context.custom_cmd.borrow(),
+                glyph_as_text,
+                SvgContext::DEFAULT_COLOR,
+            );
+            // one faulty formula does not prevent the others from being rendered
+            let (_, svg) = match rendered {
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    match line {
+                        Some(line) => eprintln!("{} (line {})", e, line),
+                        None       => eprintln!("{}", e),
+                    }
+                    continue;
+                },
+            };
+            let numbered_outfile;
+            let output = if formulas.len() == 1 { &*outfile } else {
+                numbered_outfile = numbered_output(&outfile, index + 1);
+                &numbered_outfile
+            };
+            eprintln!("Saving to {:?}", output);
+            output.stream()?.write_all(svg.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Formulas of a file with one formula per line, with their line numbers ; blank lines are skipped
+fn formula_lines(source : &str) -> Vec<(Option<usize>, String)> {
+    source.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| (Some(index + 1), line.trim().to_string()))
+        .collect()
+}
+
+/// Formula of a '.tex' snippet: comments are removed, as well as maths delimiters around the whole snippet
+/// (but not in e.g. '$a$ + $b$', where they delimit two formulas)
+pub(crate) fn tex_snippet(source : &str) -> String {
+    let without_comments : Vec<&str> = source.lines()
+        .map(|line| {
+            // '%' starts a comment, unless escaped as '\%'
+            let comment_start = line.char_indices()
+                .find(|&(index, character)| character == '%' && !line[.. index].ends_with('\\'))
+                .map(|(index, _)| index)
+                .unwrap_or(line.len());
+            &line[.. comment_start]
+        })
+        .collect();
+    let snippet = without_comments.join("\n");
+    let snippet = snippet.trim();
+
+    for (opening, closing) in [("$$", "$$"), ("\\[", "\\]"), ("\\(", "\\)"), ("$", "$")] {
+        if let Some(inner) = snippet.strip_prefix(opening).and_then(|snippet| snippet.strip_suffix(closing)) {
+            if find_unescaped(inner, closing).is_none() {
+                return inner.trim().to_string();
+            }
+        }
+    }
+    snippet.to_string()
+}
+
+/// Position of the first occurrence of 'delimiter' in 'text' which is not escaped by a backslash
+fn find_unescaped(text : &str, delimiter : &str) -> Option<usize> {
+    let mut characters = text.char_indices();
+    while let Some((index, character)) = characters.next() {
+        if text[index ..].starts_with(delimiter) {
+            return Some(index);
+        }
+        if character == '\\' {
+            characters.next();
+        }
+    }
+    None
+}
+
+/// Output of the n-th formula when the file contains several, e.g. 'out-2.svg' for 'out.svg' ; stdout stays stdout
+fn numbered_output(outfile : &Output, number : usize) -> Output {
+    match outfile {
+        Output::Stdout     => Output::Stdout,
+        Output::Path(path) => {
+            let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+            let file_name = match path.extension() {
+                Some(extension) => format!("{}-{}.{}", stem, number, extension.to_string_lossy()),
+                None            => format!("{}-{}", stem, number),
+            };
+            Output::Path(path.with_file_name(file_name))
+        },
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippet_delimiters() {
+        assert_eq!(tex_snippet("x^2"), "x^2");
+        assert_eq!(tex_snippet("$x^2$"), "x^2");
+        assert_eq!(tex_snippet("$$ x^2 $$\n"), "x^2");
+        assert_eq!(tex_snippet("\\[x\\]"), "x");
+        assert_eq!(tex_snippet("\\(x\\)"), "x");
+        assert_eq!(tex_snippet("$\\$5$"), "\\$5");
+    }
+
+    #[test]
+    fn several_formulas_keep_their_delimiters() {
+        assert_eq!(tex_snippet("$a$ + $b$"), "$a$ + $b$");
+        assert_eq!(tex_snippet("$$a$$ + $$b$$"), "$$a$$ + $$b$$");
+        assert_eq!(tex_snippet("\\(a\\) + \\(b\\)"), "\\(a\\) + \\(b\\)");
+        assert_eq!(tex_snippet("$$a$"), "$$a$");
+    }
+
+    #[test]
+    fn snippet_comments() {
+        assert_eq!(tex_snippet("% a comment\n$x % y$\n"), "$x");
+        assert_eq!(tex_snippet("% a comment\n$x$ % y\n"), "x");
+        assert_eq!(tex_snippet("50\\% % percents"), "50\\%");
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(formula_lines("a\n\n  b  \r\n \nc"), [
+            (Some(1), "a".to_string()),
+            (Some(3), "b".to_string()),
+            (Some(5), "c".to_string()),
+        ]);
+        assert!(formula_lines("\n  \n").is_empty());
+    }
+}
@@ -3,6 +3,8 @@ use std::fmt::Display;
 use rex::{error::{FontError, LayoutError}, parser::error::ParseError};
 use ttf_parser::FaceParsingError;
 
+use crate::font_loading::WoffError;
+
 
 
 
@@ -15,6 +17,13 @@ pub enum AppError {
     FontError(FontError),
     LayoutError(LayoutError),
     FaceParsingError(FaceParsingError),
+    WoffError(WoffError),
+    /// An exception thrown by JavaScript code, e.g. a callback
+    #[cfg(target_arch = "wasm32")]
+    JsError(String),
+    /// A raster image (width, height) with more pixels than we agree to allocate
+    #[cfg(target_arch = "wasm32")]
+    ImageTooLarge(usize, usize),
 }
 
 impl Display for AppError {
@@ -23,9 +32,14 @@ impl Display for AppError {
         let error_tag = match self {
             AppError::FontError(_) |
             AppError::FaceParsingError(_) |
+            AppError::WoffError(_) |
             AppError::LayoutError(LayoutError::Font(_)) => "Font Error",
             AppError::ParseError(_) => "Parse Error",
             AppError::IOError(_) => "IO Error",
+            #[cfg(target_arch = "wasm32")]
+            AppError::JsError(_) => "JavaScript Error",
+            #[cfg(target_arch = "wasm32")]
+            AppError::ImageTooLarge(..) => "Render Error",
 
             _ => "App-internal Error",
         };
@@ -36,6 +50,11 @@ impl Display for AppError {
             #[cfg(not(target_arch = "wasm32"))]
             AppError::CairoError(e)  => format!("{}", e),
             AppError::FaceParsingError(e) => format!("{}", e),
+            AppError::WoffError(e)   => format!("{}", e),
+            #[cfg(target_arch = "wasm32")]
+            AppError::JsError(e)     => e.clone(),
+            #[cfg(target_arch = "wasm32")]
+            AppError::ImageTooLarge(width, height) => format!("a {}x{} image is too large to render", width, height),
             AppError::FontError(e)   |
             AppError::LayoutError(LayoutError::Font(e)) => format!("{}", e),
         };
@@ -73,6 +92,36 @@ impl From<FaceParsingError> for AppError {
     { Self::FaceParsingError(err) }
 }
 
+#[cfg(target_arch = "wasm32")]
+impl From<owned_ttf_parser::FaceParsingError> for AppError {
+    fn from(err: owned_ttf_parser::FaceParsingError) -> Self {
+        // 'owned_ttf_parser' depends on a different version of 'ttf_parser' ; the errors are the same
+        use owned_ttf_parser::FaceParsingError as OwnedFaceParsingError;
+        let err = match err {
+            OwnedFaceParsingError::MalformedFont        => FaceParsingError::MalformedFont,
+            OwnedFaceParsingError::UnknownMagic         => FaceParsingError::UnknownMagic,
+            OwnedFaceParsingError::FaceIndexOutOfBounds => FaceParsingError::FaceIndexOutOfBounds,
+            OwnedFaceParsingError::NoHeadTable          => FaceParsingError::NoHeadTable,
+            OwnedFaceParsingError::NoHheaTable          => FaceParsingError::NoHheaTable,
+            OwnedFaceParsingError::NoMaxpTable          => FaceParsingError::NoMaxpTable,
+        };
+        Self::FaceParsingError(err)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl From<wasm_bindgen::JsValue> for AppError {
+    fn from(err: wasm_bindgen::JsValue) -> Self {
+        // JavaScript can throw anything, not only strings
+        Self::JsError(err.as_string().unwrap_or_else(|| format!("{:?}", err)))
+    }
+}
+
+impl From<WoffError> for AppError {
+    fn from(err: WoffError) -> Self 
+    { Self::WoffError(err) }
+}
+
 impl From<ParseError> for AppError {
     fn from(err: ParseError) -> Self 
     { Self::ParseError(err.to_string()) }
@@ -0,0 +1,41 @@
+use std::borrow::Cow;
+
+use ttf_parser::{Face, fonts_in_collection};
+
+use crate::error::AppResult;
+
+mod woff;
+pub use woff::WoffError;
+
+
+
+/// Decompresses WOFF and WOFF2 files into plain OpenType files.
+/// Other files are returned as is.
+pub fn decompress(data : &[u8]) -> AppResult<Cow<'_, [u8]>> {
+    match woff::signature(data) {
+        Some(woff::WOFF_SIGNATURE)  => Ok(Cow::Owned(woff::decode_woff(data)?)),
+        Some(woff::WOFF2_SIGNATURE) => Ok(Cow::Owned(woff::decode_woff2(data)?)),
+        _                           => Ok(Cow::Borrowed(data)),
+    }
+}
+
+
+/// Picks which face to load from a font file.
+/// If 'face_index' is given, it is used as is.
+/// Otherwise, if the file is a font collection (TTC/OTC), the first face with a MATH table is picked.
+/// If no face has a MATH table, the first face is returned and the error is reported when the MATH table is accessed.
+pub fn pick_face_index(data : &[u8], face_index : Option<u32>) -> u32 {
+    if let Some(face_index) = face_index {
+        return face_index;
+    }
+
+    // 'fonts_in_collection' returns None for files which aren't collections
+    let n_faces = fonts_in_collection(data).unwrap_or(1);
+    (0 .. n_faces)
+        .find(|index| {
+            Face::parse(data, *index)
+                .map(|face| face.tables().math.is_some())
+                .unwrap_or(false)
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,956 @@
+//! Decoding of WOFF and WOFF2 font files into plain OpenType (sfnt) files.
+//! Cf. https://www.w3.org/TR/WOFF/ and https://www.w3.org/TR/WOFF2/
+
+use std::fmt::Display;
+use std::io::Read;
+
+
+
+pub const WOFF_SIGNATURE  : u32 = 0x774F4646; // 'wOFF'
+pub const WOFF2_SIGNATURE : u32 = 0x774F4632; // 'wOF2'
+const TTC_FLAVOR          : u32 = 0x74746366; // 'ttcf'
+
+const GLYF_TAG : u32 = 0x676C7966;
+const LOCA_TAG : u32 = 0x6C6F6361;
+const HMTX_TAG : u32 = 0x686D7478;
+const HHEA_TAG : u32 = 0x68686561;
+const HEAD_TAG : u32 = 0x68656164;
+
+/// Offset tables of OpenType fonts can't describe 4096 tables or more
+const MAX_TABLES : u32 = 4096;
+/// Decoded fonts larger than this are rejected before anything is decompressed: a few bytes of compressed data can claim gigabytes
+const MAX_SFNT_SIZE : u64 = 64 << 20;
+
+/// Tags which WOFF2 table directory can refer to by index
+const KNOWN_TAGS : [&[u8; 4]; 63] = [
+    b"cmap", b"head", b"hhea", b"hmtx", b"maxp", b"name", b"OS/2", b"post",
+    b"cvt ", b"fpgm", b"glyf", b"loca", b"prep", b"CFF ", b"VORG", b"EBDT",
+    b"EBLC", b"gasp", b"hdmx", b"kern", b"LTSH", b"PCLT", b"VDMX", b"vhea",
+    b"vmtx", b"BASE", b"GDEF", b"GPOS", b"GSUB", b"EBSC", b"JSTF", b"MATH",
+    b"CBDT", b"CBLC", b"COLR", b"CPAL", b"SVG ", b"sbix", b"acnt", b"avar",
+    b"bdat", b"bloc", b"bsln", b"cvar", b"fdsc", b"feat", b"fmtx", b"fvar",
+    b"gvar", b"hsty", b"just", b"lcar", b"mort", b"morx", b"opbd", b"prop",
+    b"trak", b"Zapf", b"Silf", b"Glat", b"Gloc", b"Feat", b"Sill",
+];
+
+
+#[derive(Debug,)]
+pub enum WoffError {
+    Truncated,
+    Malformed(&'static str),
+    Decompression(String),
+}
+
+impl Display for WoffError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WoffError::Truncated      => write!(f, "WOFF file is truncated"),
+            WoffError::Malformed(e)   => write!(f, "malformed WOFF file ({})", e),
+            WoffError::Decompression(e) => write!(f, "could not decompress WOFF data ({})", e),
+        }
+    }
+}
+
+type WoffResult<A> = Result<A, WoffError>;
+
+
+
+
+/// Returns the signature of the file, i.e. its first 4 bytes.
+pub fn signature(data : &[u8]) -> Option<u32> {
+    Reader::new(data).u32().ok()
+}
+
+
+/// Converts a WOFF 1.0 file into an OpenType file.
+pub fn decode_woff(data : &[u8]) -> WoffResult<Vec<u8>> {
+    let mut reader = Reader::new(data);
+    if reader.u32()? != WOFF_SIGNATURE {
+        return Err(WoffError::Malformed("bad signature"));
+    }
+    let flavor     = reader.u32()?;
+    let _length    = reader.u32()?;
+    let num_tables = reader.u16()?;
+    reader.skip(2)?;  // reserved
+    let total_sfnt_size = reader.u32()?;
+    reader.skip(24)?; // versions, metadata & private blocks
+
+    let mut directory = Vec::with_capacity(num_tables.into());
+    for _ in 0 .. num_tables {
+        let tag         = reader.u32()?;
+        let offset      = reader.u32()? as usize;
+        let comp_length = reader.u32()? as usize;
+        let orig_length = reader.u32()?;
+        let checksum    = reader.u32()?;
+        directory.push((tag, offset, comp_length, orig_length, checksum));
+    }
+    check_declared_size(directory.iter().map(|(_, _, _, orig_length, _)| *orig_length), total_sfnt_size)?;
+
+    let mut tables = Vec::with_capacity(directory.len());
+    for (tag, offset, comp_length, orig_length, checksum) in directory {
+        let orig_length = orig_length as usize;
+        // offsets and lengths are untrusted: on 32-bit platforms, their sum may overflow
+        let end = offset.checked_add(comp_length).ok_or(WoffError::Truncated)?;
+        let compressed = data.get(offset .. end).ok_or(WoffError::Truncated)?;
+        let table_data =
+            if comp_length < orig_length {
+                // decompressing more than the announced length would only be rejected below
+                miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(compressed, orig_length)
+                    .map_err(|e| WoffError::Decompression(e.to_string()))?
+            }
+            else
+            { compressed.to_vec() }
+        ;
+        if table_data.len() != orig_length {
+            return Err(WoffError::Malformed("table has wrong length"));
+        }
+        tables.push(Table { tag, checksum, data : table_data });
+    }
+
+    write_sfnt(flavor, &tables)
+}
+
+
+/// Converts a WOFF2 file into an OpenType file (or an OpenType collection).
+pub fn decode_woff2(data : &[u8]) -> WoffResult<Vec<u8>> {
+    let mut reader = Reader::new(data);
+    if reader.u32()? != WOFF2_SIGNATURE {
+        return Err(WoffError::Malformed("bad signature"));
+    }
+    let flavor     = reader.u32()?;
+    let _length    = reader.u32()?;
+    let num_tables = reader.u16()?;
+    reader.skip(2)?; // reserved
+    let total_sfnt_size = reader.u32()?;
+    let total_compressed_size = reader.u32()? as usize;
+    reader.skip(24)?; // versions, metadata & private blocks
+
+    // -- Table directory
+    let mut entries = Vec::with_capacity(num_tables.into());
+    for _ in 0 .. num_tables {
+        let flags = reader.u8()?;
+        let tag = match flags & 0x3F {
+            0x3F  => reader.u32()?,
+            index => u32::from_be_bytes(*KNOWN_TAGS[usize::from(index)]),
+        };
+        let transform_version = flags >> 6;
+        let orig_length = reader.uint_base128()?;
+        // glyf & loca are transformed unless version is 3 ; other tables are transformed unless version is 0
+        let transformed = match tag {
+            GLYF_TAG | LOCA_TAG => transform_version != 3,
+            _                   => transform_version != 0,
+        };
+        let transform_length = if transformed { reader.uint_base128()? } else { orig_length };
+        entries.push(Woff2Entry { tag, orig_length, transform_length, transformed });
+    }
+
+    check_declared_size(entries.iter().map(|entry| entry.orig_length), total_sfnt_size)?;
+
+    // -- Collection directory
+    let fonts =
+        if flavor == TTC_FLAVOR {
+            let _version  = reader.u32()?;
+            let num_fonts = reader.u255_u16()?;
+            let mut fonts = Vec::with_capacity(num_fonts.into());
+            for _ in 0 .. num_fonts {
+                let num_tables = reader.u255_u16()?;
+                let flavor     = reader.u32()?;
+                let mut indices = Vec::with_capacity(num_tables.into());
+                for _ in 0 .. num_tables {
+                    let index = usize::from(reader.u255_u16()?);
+                    if index >= entries.len() {
+                        return Err(WoffError::Malformed("collection refers to non-existent table"));
+                    }
+                    indices.push(index);
+                }
+                fonts.push(FontEntry { flavor, indices });
+            }
+            fonts
+        }
+        else {
+            vec![FontEntry { flavor, indices : (0 .. entries.len()).collect() }]
+        }
+    ;
+
+    // -- Decompress table data
+    let compressed = reader.take(total_compressed_size)?;
+    // the stream holds the tables back to back: anything past their total length is an error, and isn't decompressed
+    let stream_length : u64 = entries.iter().map(|entry| u64::from(entry.transform_length)).sum();
+    if stream_length > MAX_SFNT_SIZE {
+        return Err(WoffError::Malformed("font is too large"));
+    }
+    let mut stream = Vec::new();
+    brotli_decompressor::Decompressor::new(compressed, 4096)
+        .take(stream_length + 1)
+        .read_to_end(&mut stream)
+        .map_err(|e| WoffError::Decompression(e.to_string()))?;
+    if stream.len() as u64 > stream_length {
+        return Err(WoffError::Malformed("decompressed data is longer than the tables"));
+    }
+
+    let mut stream = Reader::new(&stream);
+    let mut raw_tables = Vec::with_capacity(entries.len());
+    for entry in entries.iter() {
+        raw_tables.push(stream.take(entry.transform_length as usize)?);
+    }
+
+    // -- Undo transformations
+    let mut tables : Vec<Option<Vec<u8>>> = vec![None; entries.len()];
+    for (index, entry) in entries.iter().enumerate() {
+        if !entry.transformed {
+            tables[index] = Some(raw_tables[index].to_vec());
+        }
+        else if entry.tag == GLYF_TAG {
+            let font = fonts.iter().find(|font| font.indices.contains(&index)).ok_or(WoffError::Malformed("orphan glyf table"))?;
+            let loca_index = font.find(&entries, LOCA_TAG).ok_or(WoffError::Malformed("glyf table without loca table"))?;
+            let (glyf, loca) = reconstruct_glyf(raw_tables[index])?;
+            if loca.len() != entries[loca_index].orig_length as usize {
+                return Err(WoffError::Malformed("reconstructed loca table has wrong length"));
+            }
+            tables[index]      = Some(glyf);
+            tables[loca_index] = Some(loca);
+        }
+    }
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.transformed && entry.tag == HMTX_TAG {
+            let font = fonts.iter().find(|font| font.indices.contains(&index)).ok_or(WoffError::Malformed("orphan hmtx table"))?;
+            let table_data = |tag| {
+                font.find(&entries, tag)
+                    .and_then(|index| tables[index].as_deref())
+                    .ok_or(WoffError::Malformed("hmtx transform requires glyf, loca, head & hhea tables"))
+            };
+            let hmtx = reconstruct_hmtx(
+                raw_tables[index],
+                table_data(GLYF_TAG)?,
+                table_data(LOCA_TAG)?,
+                table_data(HEAD_TAG)?,
+                table_data(HHEA_TAG)?,
+            )?;
+            tables[index] = Some(hmtx);
+        }
+    }
+    let tables =
+        entries.iter()
+        .zip(tables)
+        .map(|(entry, table)| {
+            let data = table.ok_or(WoffError::Malformed("unsupported table transformation"))?;
+            Ok(Table { tag : entry.tag, checksum : checksum(&data), data })
+        })
+        .collect::<WoffResult<Vec<_>>>()?
+    ;
+
+    if flavor == TTC_FLAVOR {
+        write_collection(&fonts, &tables)
+    }
+    else {
+        write_sfnt(flavor, &tables)
+    }
+}
+
+
+/// Checks the lengths of the tables against the size of the decoded font, as declared in the header, and against 'MAX_SFNT_SIZE'
+fn check_declared_size(orig_lengths : impl Iterator<Item = u32>, total_sfnt_size : u32) -> WoffResult<()> {
+    let tables_size : u64 = orig_lengths.map(u64::from).sum();
+    if tables_size > u64::from(total_sfnt_size) {
+        return Err(WoffError::Malformed("tables are larger than the declared font size"));
+    }
+    if tables_size > MAX_SFNT_SIZE {
+        return Err(WoffError::Malformed("font is too large"));
+    }
+    Ok(())
+}
+
+
+struct Woff2Entry {
+    tag : u32,
+    orig_length : u32,
+    transform_length : u32,
+    transformed : bool,
+}
+
+struct FontEntry {
+    flavor  : u32,
+    indices : Vec<usize>,
+}
+
+impl FontEntry {
+    fn find(&self, entries : &[Woff2Entry], tag : u32) -> Option<usize> {
+        self.indices.iter().copied().find(|index| entries[*index].tag == tag)
+    }
+}
+
+
+struct Table {
+    tag      : u32,
+    checksum : u32,
+    data     : Vec<u8>,
+}
+
+
+fn write_sfnt(flavor : u32, tables : &[Table]) -> WoffResult<Vec<u8>> {
+    let header_size = 12 + 16 * tables.len();
+    let total_size  = header_size + tables.iter().map(|table| padded_len(table.data.len())).sum::<usize>();
+    let mut output  = Vec::with_capacity(total_size);
+
+    let indices : Vec<usize> = (0 .. tables.len()).collect();
+    write_offset_table(&mut output, flavor, tables, &indices, &table_offsets(tables, header_size))?;
+    for table in tables {
+        write_padded(&mut output, &table.data);
+    }
+    Ok(output)
+}
+
+fn write_collection(fonts : &[FontEntry], tables : &[Table]) -> WoffResult<Vec<u8>> {
+    // TTC header, then an offset table for every font, then the shared table data.
+    let ttc_header_size = 12 + 4 * fonts.len();
+    let offset_table_sizes : Vec<usize> = fonts.iter().map(|font| 12 + 16 * font.indices.len()).collect();
+    let header_size = ttc_header_size + offset_table_sizes.iter().sum::<usize>();
+    let offsets = table_offsets(tables, header_size);
+
+    let mut output = Vec::new();
+    output.extend_from_slice(&TTC_FLAVOR.to_be_bytes());
+    output.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+    output.extend_from_slice(&(fonts.len() as u32).to_be_bytes());
+    let mut offset_table_position = ttc_header_size;
+    for size in offset_table_sizes.iter() {
+        output.extend_from_slice(&(offset_table_position as u32).to_be_bytes());
+        offset_table_position += size;
+    }
+    for font in fonts {
+        write_offset_table(&mut output, font.flavor, tables, &font.indices, &offsets)?;
+    }
+    for table in tables {
+        write_padded(&mut output, &table.data);
+    }
+    Ok(output)
+}
+
+fn table_offsets(tables : &[Table], header_size : usize) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(tables.len());
+    let mut offset  = header_size;
+    for table in tables {
+        offsets.push(offset);
+        offset += padded_len(table.data.len());
+    }
+    offsets
+}
+
+fn write_offset_table(output : &mut Vec<u8>, flavor : u32, tables : &[Table], indices : &[usize], offsets : &[usize]) -> WoffResult<()> {
+    // 'searchRange' and 'rangeShift' are 16 * (number of tables), as 16-bit integers
+    let num_tables = u32::try_from(indices.len()).ok().filter(|num_tables| *num_tables < MAX_TABLES).ok_or(WoffError::Malformed("too many tables"))?;
+    let entry_selector = if num_tables == 0 { 0 } else { 31 - num_tables.leading_zeros() };
+    let search_range   = (1u32 << entry_selector) * 16;
+    let range_shift    = num_tables * 16 - search_range;
+    let [num_tables, search_range, entry_selector, range_shift] = [num_tables, search_range, entry_selector, range_shift].map(|value| value as u16);
+
+    output.extend_from_slice(&flavor.to_be_bytes());
+    output.extend_from_slice(&num_tables.to_be_bytes());
+    output.extend_from_slice(&search_range.to_be_bytes());
+    output.extend_from_slice(&entry_selector.to_be_bytes());
+    output.extend_from_slice(&range_shift.to_be_bytes());
+
+    // Table records must be sorted by tag
+    let mut indices = indices.to_vec();
+    indices.sort_by_key(|index| tables[*index].tag);
+    for index in indices {
+        let table = &tables[index];
+        output.extend_from_slice(&table.tag.to_be_bytes());
+        output.extend_from_slice(&table.checksum.to_be_bytes());
+        output.extend_from_slice(&(offsets[index] as u32).to_be_bytes());
+        output.extend_from_slice(&(table.data.len() as u32).to_be_bytes());
+    }
+    Ok(())
+}
+
+fn write_padded(output : &mut Vec<u8>, data : &[u8]) {
+    output.extend_from_slice(data);
+    output.resize(output.len() + padded_len(data.len()) - data.len(), 0);
+}
+
+fn padded_len(length : usize) -> usize {
+    (length + 3) & !3
+}
+
+fn checksum(data : &[u8]) -> u32 {
+    data.chunks(4).fold(0u32, |sum, chunk| {
+        let mut word = [0u8; 4];
+        word[.. chunk.len()].copy_from_slice(chunk);
+        sum.wrapping_add(u32::from_be_bytes(word))
+    })
+}
+
+
+
+
+// Flags of simple glyphs in the 'glyf' table
+const ON_CURVE_POINT : u8 = 0x01;
+const X_SHORT_VECTOR : u8 = 0x02;
+const Y_SHORT_VECTOR : u8 = 0x04;
+const X_IS_SAME_OR_POSITIVE : u8 = 0x10;
+const Y_IS_SAME_OR_POSITIVE : u8 = 0x20;
+const OVERLAP_SIMPLE : u8 = 0x40;
+
+// Flags of composite glyphs in the 'glyf' table
+const ARG_1_AND_2_ARE_WORDS    : u16 = 0x0001;
+const WE_HAVE_A_SCALE          : u16 = 0x0008;
+const MORE_COMPONENTS          : u16 = 0x0020;
+const WE_HAVE_AN_X_AND_Y_SCALE : u16 = 0x0040;
+const WE_HAVE_A_TWO_BY_TWO     : u16 = 0x0080;
+const WE_HAVE_INSTRUCTIONS     : u16 = 0x0100;
+
+
+/// Rebuilds the 'glyf' and 'loca' tables from a transformed 'glyf' table
+fn reconstruct_glyf(data : &[u8]) -> WoffResult<(Vec<u8>, Vec<u8>)> {
+    let mut header = Reader::new(data);
+    let _version      = header.u16()?;
+    let option_flags  = header.u16()?;
+    let num_glyphs    = usize::from(header.u16()?);
+    let index_format  = header.u16()?;
+
+    let mut stream_sizes = [0usize; 7];
+    for size in stream_sizes.iter_mut() {
+        *size = header.u32()? as usize;
+    }
+    let [n_contour_size, n_points_size, flag_size, glyph_size, composite_size, bbox_size, instruction_size] = stream_sizes;
+    let mut n_contour_stream   = Reader::new(header.take(n_contour_size)?);
+    let mut n_points_stream    = Reader::new(header.take(n_points_size)?);
+    let mut flag_stream        = Reader::new(header.take(flag_size)?);
+    let mut glyph_stream       = Reader::new(header.take(glyph_size)?);
+    let mut composite_stream   = Reader::new(header.take(composite_size)?);
+    let mut bbox_stream        = Reader::new(header.take(bbox_size)?);
+    let mut instruction_stream = Reader::new(header.take(instruction_size)?);
+    let overlap_bitmap =
+        if option_flags & 1 != 0 { Some(header.take(num_glyphs.div_ceil(8))?) }
+        else { None }
+    ;
+
+    let bbox_bitmap = bbox_stream.take(4 * num_glyphs.div_ceil(32))?;
+    let has_bit = |bitmap : &[u8], index : usize| bitmap[index / 8] & (0x80 >> (index % 8)) != 0;
+
+    let mut glyf = Vec::new();
+    let mut loca_offsets = Vec::with_capacity(num_glyphs + 1);
+
+    for glyph_index in 0 .. num_glyphs {
+        loca_offsets.push(glyf.len());
+        let n_contours = n_contour_stream.i16()?;
+        let has_bbox   = has_bit(bbox_bitmap, glyph_index);
+
+        match n_contours {
+            0 => {
+                if has_bbox {
+                    return Err(WoffError::Malformed("empty glyph with bounding box"));
+                }
+            },
+            -1 => {
+                // composite glyph
+                if !has_bbox {
+                    return Err(WoffError::Malformed("composite glyph without bounding box"));
+                }
+                let (components, have_instructions) = composite_glyph_data(&mut composite_stream)?;
+                glyf.extend_from_slice(&n_contours.to_be_bytes());
+                glyf.extend_from_slice(bbox_stream.take(8)?);
+                glyf.extend_from_slice(components);
+                if have_instructions {
+                    let instruction_length = glyph_stream.u255_u16()?;
+                    glyf.extend_from_slice(&instruction_length.to_be_bytes());
+                    glyf.extend_from_slice(instruction_stream.take(instruction_length.into())?);
+                }
+            },
+            n_contours if n_contours > 0 => {
+                // simple glyph
+                let mut end_points = Vec::with_capacity(n_contours as usize);
+                let mut n_points : usize = 0;
+                for _ in 0 .. n_contours {
+                    n_points += usize::from(n_points_stream.u255_u16()?);
+                    let end_point = 
+                        n_points.checked_sub(1)
+                        .and_then(|end_point| u16::try_from(end_point).ok())
+                        .ok_or(WoffError::Malformed("invalid number of points in glyph"))?
+                    ;
+                    end_points.push(end_point);
+                }
+                let points = decode_triplets(flag_stream.take(n_points)?, &mut glyph_stream)?;
+                let instruction_length = glyph_stream.u255_u16()?;
+                let instructions = instruction_stream.take(instruction_length.into())?;
+
+                glyf.extend_from_slice(&n_contours.to_be_bytes());
+                if has_bbox {
+                    glyf.extend_from_slice(bbox_stream.take(8)?);
+                }
+                else {
+                    let x_min = points.iter().map(|p| p.x).min().unwrap_or(0);
+                    let y_min = points.iter().map(|p| p.y).min().unwrap_or(0);
+                    let x_max = points.iter().map(|p| p.x).max().unwrap_or(0);
+                    let y_max = points.iter().map(|p| p.y).max().unwrap_or(0);
+                    for value in [x_min, y_min, x_max, y_max] {
+                        glyf.extend_from_slice(&(value as i16).to_be_bytes());
+                    }
+                }
+                for end_point in end_points {
+                    glyf.extend_from_slice(&end_point.to_be_bytes());
+                }
+                glyf.extend_from_slice(&instruction_length.to_be_bytes());
+                glyf.extend_from_slice(instructions);
+                let overlaps = overlap_bitmap.map(|bitmap| has_bit(bitmap, glyph_index)).unwrap_or(false);
+                write_simple_glyph_points(&mut glyf, &points, overlaps);
+            },
+            _ => return Err(WoffError::Malformed("invalid number of contours")),
+        }
+
+        // pad glyph to 4 bytes
+        glyf.resize(padded_len(glyf.len()), 0);
+    }
+    loca_offsets.push(glyf.len());
+
+    let mut loca = Vec::with_capacity(loca_offsets.len() * 4);
+    for offset in loca_offsets {
+        if index_format == 0 {
+            let offset = u16::try_from(offset / 2).map_err(|_| WoffError::Malformed("glyf table too big for short loca format"))?;
+            loca.extend_from_slice(&offset.to_be_bytes());
+        }
+        else {
+            loca.extend_from_slice(&(offset as u32).to_be_bytes());
+        }
+    }
+
+    Ok((glyf, loca))
+}
+
+
+#[derive(Debug, Clone, Copy)]
+struct Point {
+    x : i32,
+    y : i32,
+    on_curve : bool,
+}
+
+fn decode_triplets(flags : &[u8], glyph_stream : &mut Reader) -> WoffResult<Vec<Point>> {
+    fn with_sign(flag : u8, value : i32) -> i32 {
+        if flag & 1 != 0 { value } else { - value }
+    }
+
+    let mut points = Vec::with_capacity(flags.len());
+    let (mut x, mut y) = (0i32, 0i32);
+    for &flag in flags {
+        let on_curve = flag >> 7 == 0;
+        let flag = flag & 0x7F;
+        let (dx, dy);
+        if flag < 10 {
+            let b0 = i32::from(glyph_stream.u8()?);
+            dx = 0;
+            dy = with_sign(flag, (i32::from(flag & 14) << 7) + b0);
+        }
+        else if flag < 20 {
+            let b0 = i32::from(glyph_stream.u8()?);
+            dx = with_sign(flag, (i32::from((flag - 10) & 14) << 7) + b0);
+            dy = 0;
+        }
+        else if flag < 84 {
+            let b0 = i32::from(flag - 20);
+            let b1 = i32::from(glyph_stream.u8()?);
+            dx = with_sign(flag,      1 + (b0 & 0x30) + (b1 >> 4));
+            dy = with_sign(flag >> 1, 1 + ((b0 & 0x0C) << 2) + (b1 & 0x0F));
+        }
+        else if flag < 120 {
+            let b0 = i32::from(flag - 84);
+            let b1 = i32::from(glyph_stream.u8()?);
+            let b2 = i32::from(glyph_stream.u8()?);
+            dx = with_sign(flag,      1 + ((b0 / 12) << 8) + b1);
+            dy = with_sign(flag >> 1, 1 + (((b0 % 12) >> 2) << 8) + b2);
+        }
+        else if flag < 124 {
+            let b1 = i32::from(glyph_stream.u8()?);
+            let b2 = i32::from(glyph_stream.u8()?);
+            let b3 = i32::from(glyph_stream.u8()?);
+            dx = with_sign(flag,      (b1 << 4) + (b2 >> 4));
+            dy = with_sign(flag >> 1, ((b2 & 0x0F) << 8) + b3);
+        }
+        else {
+            let b1 = i32::from(glyph_stream.u16()?);
+            let b2 = i32::from(glyph_stream.u16()?);
+            dx = with_sign(flag,      b1);
+            dy = with_sign(flag >> 1, b2);
+        }
+        x += dx;
+        y += dy;
+        points.push(Point { x, y, on_curve });
+    }
+    Ok(points)
+}
+
+/// Writes flags & coordinates of a simple glyph in the format of the 'glyf' table.
+fn write_simple_glyph_points(glyf : &mut Vec<u8>, points : &[Point], overlaps : bool) {
+    let mut flags  = Vec::with_capacity(points.len());
+    let mut x_data = Vec::with_capacity(2 * points.len());
+    let mut y_data = Vec::with_capacity(2 * points.len());
+
+    let (mut last_x, mut last_y) = (0, 0);
+    for (index, point) in points.iter().enumerate() {
+        let mut flag = if point.on_curve { ON_CURVE_POINT } else { 0 };
+        if overlaps && index == 0 {
+            flag |= OVERLAP_SIMPLE;
+        }
+        let dx = point.x - last_x;
+        let dy = point.y - last_y;
+        flag |= encode_coordinate(dx, &mut x_data, X_SHORT_VECTOR, X_IS_SAME_OR_POSITIVE);
+        flag |= encode_coordinate(dy, &mut y_data, Y_SHORT_VECTOR, Y_IS_SAME_OR_POSITIVE);
+        flags.push(flag);
+        last_x = point.x;
+        last_y = point.y;
+    }
+
+    glyf.extend_from_slice(&flags);
+    glyf.extend_from_slice(&x_data);
+    glyf.extend_from_slice(&y_data);
+}
+
+fn encode_coordinate(delta : i32, data : &mut Vec<u8>, short_flag : u8, same_or_positive_flag : u8) -> u8 {
+    if delta == 0 {
+        same_or_positive_flag
+    }
+    else if delta.abs() < 256 {
+        data.push(delta.unsigned_abs() as u8);
+        if delta > 0 { short_flag | same_or_positive_flag } else { short_flag }
+    }
+    else {
+        data.extend_from_slice(&(delta as i16).to_be_bytes());
+        0
+    }
+}
+
+/// Reads the components of a composite glyph ; returns the raw data and whether the glyph has instructions.
+fn composite_glyph_data<'a>(composite_stream : &mut Reader<'a>) -> WoffResult<(&'a [u8], bool)> {
+    let start = composite_stream.clone();
+    let mut size = 0;
+    let mut have_instructions = false;
+    loop {
+        let flags = composite_stream.u16()?;
+        have_instructions |= flags & WE_HAVE_INSTRUCTIONS != 0;
+        let mut component_size = 4; // flags and glyph index
+        component_size += if flags & ARG_1_AND_2_ARE_WORDS != 0 { 4 } else { 2 };
+        if flags & WE_HAVE_A_SCALE != 0 {
+            component_size += 2;
+        }
+        else if flags & WE_HAVE_AN_X_AND_Y_SCALE != 0 {
+            component_size += 4;
+        }
+        else if flags & WE_HAVE_A_TWO_BY_TWO != 0 {
+            component_size += 8;
+        }
+        composite_stream.skip(component_size - 2)?;
+        size += component_size;
+        if flags & MORE_COMPONENTS == 0 {
+            break;
+        }
+    }
+    Ok((start.peek(size)?, have_instructions))
+}
+
+
+/// Rebuilds the 'hmtx' table from a transformed 'hmtx' table
+fn reconstruct_hmtx(data : &[u8], glyf : &[u8], loca : &[u8], head : &[u8], hhea : &[u8]) -> WoffResult<Vec<u8>> {
+    let mut reader = Reader::new(data);
+    let flags = reader.u8()?;
+    let proportional_lsb_absent = flags & 1 != 0;
+    let monospaced_lsb_absent   = flags & 2 != 0;
+
+    let mut hhea = Reader::new(hhea);
+    hhea.skip(34)?;
+    let num_h_metrics = usize::from(hhea.u16()?);
+    let mut head = Reader::new(head);
+    head.skip(50)?;
+    let long_loca = head.i16()? != 0;
+
+    // 'x_min' of every glyph, as found in the 'glyf' table
+    let loca_offsets : Vec<usize> =
+        if long_loca { loca.chunks_exact(4).map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]) as usize).collect() }
+        else         { loca.chunks_exact(2).map(|c| 2 * usize::from(u16::from_be_bytes([c[0], c[1]]))).collect() }
+    ;
+    let num_glyphs = loca_offsets.len().saturating_sub(1);
+    if num_h_metrics > num_glyphs {
+        return Err(WoffError::Malformed("more metrics than glyphs"));
+    }
+    let x_min = |glyph_index : usize| -> WoffResult<i16> {
+        let (start, end) = (loca_offsets[glyph_index], loca_offsets[glyph_index + 1]);
+        if start == end {
+            return Ok(0);
+        }
+        let mut glyph = Reader::new(glyf.get(start .. end).ok_or(WoffError::Truncated)?);
+        glyph.skip(2)?;
+        glyph.i16()
+    };
+
+    let mut advances = Vec::with_capacity(num_h_metrics);
+    for _ in 0 .. num_h_metrics {
+        advances.push(reader.u16()?);
+    }
+
+    let mut hmtx = Vec::with_capacity(4 * num_h_metrics + 2 * (num_glyphs - num_h_metrics));
+    for (glyph_index, advance) in advances.into_iter().enumerate() {
+        let lsb = if proportional_lsb_absent { x_min(glyph_index)? } else { reader.i16()? };
+        hmtx.extend_from_slice(&advance.to_be_bytes());
+        hmtx.extend_from_slice(&lsb.to_be_bytes());
+    }
+    for glyph_index in num_h_metrics .. num_glyphs {
+        let lsb = if monospaced_lsb_absent { x_min(glyph_index)? } else { reader.i16()? };
+        hmtx.extend_from_slice(&lsb.to_be_bytes());
+    }
+    Ok(hmtx)
+}
+
+
+
+
+/// A cursor over big-endian binary data
+#[derive(Clone)]
+struct Reader<'a> {
+    data : &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data : &'a [u8]) -> Self { Self { data } }
+
+    fn take(&mut self, n : usize) -> WoffResult<&'a [u8]> {
+        let result = self.peek(n)?;
+        self.data = &self.data[n ..];
+        Ok(result)
+    }
+
+    fn peek(&self, n : usize) -> WoffResult<&'a [u8]> {
+        self.data.get(.. n).ok_or(WoffError::Truncated)
+    }
+
+    fn skip(&mut self, n : usize) -> WoffResult<()> {
+        self.take(n)?;
+        Ok(())
+    }
+
+    fn u8(&mut self) -> WoffResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> WoffResult<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn i16(&mut self) -> WoffResult<i16> {
+        Ok(self.u16()? as i16)
+    }
+
+    fn u32(&mut self) -> WoffResult<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Variable-length encoding of 32-bit unsigned integers used in WOFF2
+    fn uint_base128(&mut self) -> WoffResult<u32> {
+        let mut accumulator : u32 = 0;
+        for i in 0 .. 5 {
+            let byte = self.u8()?;
+            if i == 0 && byte == 0x80 {
+                return Err(WoffError::Malformed("UIntBase128 with leading zeros"));
+            }
+            if accumulator & 0xFE00_0000 != 0 {
+                return Err(WoffError::Malformed("UIntBase128 overflow"));
+            }
+            accumulator = (accumulator << 7) | u32::from(byte & 0x7F);
+            if byte & 0x80 == 0 {
+                return Ok(accumulator);
+            }
+        }
+        Err(WoffError::Malformed("UIntBase128 longer than 5 bytes"))
+    }
+
+    /// Variable-length encoding of 16-bit unsigned integers used in WOFF2
+    fn u255_u16(&mut self) -> WoffResult<u16> {
+        const WORD_CODE           : u8  = 253;
+        const ONE_MORE_BYTE_CODE2 : u8  = 254;
+        const ONE_MORE_BYTE_CODE1 : u8  = 255;
+        const LOWEST_U_CODE       : u16 = 253;
+
+        match self.u8()? {
+            WORD_CODE           => self.u16(),
+            ONE_MORE_BYTE_CODE1 => Ok(u16::from(self.u8()?) + LOWEST_U_CODE),
+            ONE_MORE_BYTE_CODE2 => Ok(u16::from(self.u8()?) + 2 * LOWEST_U_CODE),
+            code                => Ok(code.into()),
+        }
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A WOFF 1.0 file with the given tables ; tables are compressed if 'compress' is set
+    fn woff_file(tables : &[(&[u8; 4], &[u8], bool)]) -> Vec<u8> {
+        let directory_end = 44 + 20 * tables.len();
+        let mut directory = Vec::new();
+        let mut table_data = Vec::new();
+        for (tag, data, compress) in tables {
+            let stored = if *compress { miniz_oxide::deflate::compress_to_vec_zlib(data, 6) } else { data.to_vec() };
+            directory.extend_from_slice(*tag);
+            directory.extend_from_slice(&((directory_end + table_data.len()) as u32).to_be_bytes());
+            directory.extend_from_slice(&(stored.len() as u32).to_be_bytes());
+            directory.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            directory.extend_from_slice(&checksum(data).to_be_bytes());
+            write_padded(&mut table_data, &stored);
+        }
+
+        let mut file = Vec::new();
+        file.extend_from_slice(&WOFF_SIGNATURE.to_be_bytes());
+        file.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+        file.extend_from_slice(&((directory_end + table_data.len()) as u32).to_be_bytes());
+        file.extend_from_slice(&(tables.len() as u16).to_be_bytes());
+        file.extend_from_slice(&[0, 0]);
+        let total_sfnt_size = 12 + 16 * tables.len() + tables.iter().map(|(_, data, _)| padded_len(data.len())).sum::<usize>();
+        file.extend_from_slice(&(total_sfnt_size as u32).to_be_bytes());
+        file.resize(44, 0);
+        file.extend_from_slice(&directory);
+        file.extend_from_slice(&table_data);
+        file
+    }
+
+    #[test]
+    fn truncated_headers() {
+        for length in [0, 4, 12, 43] {
+            let file = woff_file(&[])[.. length.min(44)].to_vec();
+            assert!(decode_woff(&file).is_err(), "WOFF header of {} bytes", length);
+        }
+        let mut woff2 = WOFF2_SIGNATURE.to_be_bytes().to_vec();
+        woff2.resize(20, 0);
+        assert!(matches!(decode_woff2(&woff2), Err(WoffError::Truncated)));
+        assert!(matches!(decode_woff(&woff_file(&[(b"MATH", b"data", false)])[.. 60]), Err(WoffError::Truncated)));
+    }
+
+    #[test]
+    fn table_out_of_file() {
+        let mut file = woff_file(&[(b"MATH", b"data", false)]);
+        // offset of the table, then its length, which sum past 2^32
+        file[48 .. 52].copy_from_slice(&0xFFFF_FFF0u32.to_be_bytes());
+        file[52 .. 56].copy_from_slice(&0x20u32.to_be_bytes());
+        assert!(matches!(decode_woff(&file), Err(WoffError::Truncated)));
+    }
+
+    #[test]
+    fn decompressed_table_longer_than_announced() {
+        let mut file = woff_file(&[(b"MATH", &[0; 1000], true)]);
+        // the original length is announced as 100 bytes, more than the compressed data
+        file[56 .. 60].copy_from_slice(&100u32.to_be_bytes());
+        assert!(matches!(decode_woff(&file), Err(WoffError::Decompression(_))));
+    }
+
+    #[test]
+    fn oversized_tables() {
+        // a table claiming 1 GiB, larger than the font as declared in the header
+        let mut file = woff_file(&[(b"MATH", &[0; 1000], true)]);
+        file[56 .. 60].copy_from_slice(&(1u32 << 30).to_be_bytes());
+        assert!(matches!(decode_woff(&file), Err(WoffError::Malformed("tables are larger than the declared font size"))));
+        // the same, with a header which agrees
+        file[16 .. 20].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(decode_woff(&file), Err(WoffError::Malformed("font is too large"))));
+
+        // a WOFF2 file whose only table claims 1 GiB
+        let mut woff2 = WOFF2_SIGNATURE.to_be_bytes().to_vec();
+        woff2.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+        woff2.extend_from_slice(&0u32.to_be_bytes());
+        woff2.extend_from_slice(&1u16.to_be_bytes());
+        woff2.extend_from_slice(&[0, 0]);
+        woff2.extend_from_slice(&u32::MAX.to_be_bytes());
+        woff2.resize(48, 0);
+        // 'cmap', untransformed, with its length as a UIntBase128: 2^30 = 4 * 2^28
+        woff2.extend_from_slice(&[0x00, 0x84, 0x80, 0x80, 0x80, 0x00]);
+        assert!(matches!(decode_woff2(&woff2), Err(WoffError::Malformed("font is too large"))));
+    }
+
+    #[test]
+    fn too_many_tables() {
+        let tables : Vec<Table> = (0 .. MAX_TABLES).map(|tag| Table { tag, checksum : 0, data : Vec::new() }).collect();
+        assert!(matches!(write_sfnt(0x0001_0000, &tables), Err(WoffError::Malformed(_))));
+        assert!(write_sfnt(0x0001_0000, &tables[.. 4095]).is_ok());
+    }
+
+    #[test]
+    fn woff_to_sfnt() {
+        let math = b"MATH table, long enough to be worth compressing: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        let font = decode_woff(&woff_file(&[(b"head", b"head", false), (b"MATH", math, true), (b"cmap", b"odd", false)])).unwrap();
+
+        let mut reader = Reader::new(&font);
+        assert_eq!(reader.u32().unwrap(), 0x0001_0000);
+        assert_eq!(reader.u16().unwrap(), 3);
+        // search range, entry selector, range shift
+        assert_eq!([reader.u16().unwrap(), reader.u16().unwrap(), reader.u16().unwrap()], [32, 1, 16]);
+
+        let mut records = Vec::new();
+        for _ in 0 .. 3 {
+            let tag = reader.take(4).unwrap().to_vec();
+            let _checksum = reader.u32().unwrap();
+            let offset = reader.u32().unwrap() as usize;
+            let length = reader.u32().unwrap() as usize;
+            assert_eq!(offset % 4, 0);
+            records.push((tag, font[offset .. offset + length].to_vec()));
+        }
+        // records are sorted by tag
+        assert_eq!(records, vec![
+            (b"MATH".to_vec(), math.to_vec()),
+            (b"cmap".to_vec(), b"odd".to_vec()),
+            (b"head".to_vec(), b"head".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn glyf_and_loca_round_trip() {
+        // an empty glyph, then a triangle (20, 0), (20, 100), (70, 100) without bounding box
+        let n_contour_stream : &[u8] = &[0, 0, 0, 1];
+        let n_points_stream  : &[u8] = &[3];
+        let flag_stream      : &[u8] = &[11, 1, 11];
+        let glyph_stream     : &[u8] = &[20, 100, 50, 0];
+        let bbox_stream      : &[u8] = &[0; 4];
+        let streams = [n_contour_stream, n_points_stream, flag_stream, glyph_stream, &[], bbox_stream, &[]];
+
+        let mut transformed = Vec::new();
+        for value in [0u16, 0, 2, 0] {
+            transformed.extend_from_slice(&value.to_be_bytes());
+        }
+        for stream in streams {
+            transformed.extend_from_slice(&(stream.len() as u32).to_be_bytes());
+        }
+        for stream in streams {
+            transformed.extend_from_slice(stream);
+        }
+
+        let (glyf, loca) = reconstruct_glyf(&transformed).unwrap();
+        let triangle : &[u8] = &[
+            0, 1,                       // number of contours
+            0, 20, 0, 0, 0, 70, 0, 100, // bounding box, computed from the points
+            0, 2,                       // end points of contours
+            0, 0,                       // instructions
+            0x33, 0x35, 0x33,           // flags
+            20, 50,                     // x coordinates
+            100,                        // y coordinates
+        ];
+        assert_eq!(glyf, triangle);
+        // short offsets, halved
+        assert_eq!(loca, [0, 0, 0, 0, 0, 10]);
+
+        // the left side bearing of the transformed 'hmtx' table is the 'x_min' of the glyph
+        let mut head = vec![0; 54];
+        head[50 .. 52].copy_from_slice(&0u16.to_be_bytes());
+        let mut hhea = vec![0; 36];
+        hhea[34 .. 36].copy_from_slice(&2u16.to_be_bytes());
+        let hmtx = reconstruct_hmtx(&[1, 0, 10, 0, 60], &glyf, &loca, &head, &hhea).unwrap();
+        assert_eq!(hmtx, [0, 10, 0, 0, 0, 60, 0, 20]);
+    }
+
+    #[test]
+    fn truncated_glyf_streams() {
+        // the header announces a stream of contours longer than the table
+        let mut transformed = vec![0, 0, 0, 0, 0, 1, 0, 0];
+        transformed.extend_from_slice(&100u32.to_be_bytes());
+        transformed.resize(8 + 4 * 7, 0);
+        assert!(matches!(reconstruct_glyf(&transformed), Err(WoffError::Truncated)));
+    }
+}
@@ -3,6 +3,8 @@ mod geometry;
 mod render;
 mod svg;
 mod glyph_to_character;
+pub mod font_loading;
+mod macros;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub mod desktop;
@@ -0,0 +1,437 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::collections::HashMap;
+use std::ops::Range;
+
+use rex::parser::macros::CommandCollection;
+use rex::parser::{parse_with_custom_commands, ParseNode};
+
+use crate::error::{AppError, AppResult};
+
+
+
+/// Custom commands, along with the source they were parsed from, so that they can be extended by the definitions of a formula.
+#[derive(Default)]
+pub struct CustomCommands {
+    source     : String,
+    collection : CommandCollection,
+}
+
+impl CustomCommands {
+    /// Parses the content of a style file, cf 'parse_style_file'
+    pub fn parse(source : &str) -> AppResult<Self> {
+        Ok(Self { source: source.to_string(), collection: parse_style_file(source)? })
+    }
+
+    /// Merges style files, given with a label (e.g. their path) in order of increasing precedence:
+    /// the definitions of a file replace the definitions of the same commands in earlier files.
+    /// Returns the custom commands, along with a warning for each command which is redefined.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn layered<'a>(files : impl IntoIterator<Item = (&'a str, &'a str)>) -> AppResult<(Self, Vec<String>)> {
+        let mut source = String::new();
+        // label of the file where each command was last defined
+        let mut defined_in : HashMap<&str, &str> = HashMap::new();
+        let mut warnings = Vec::new();
+
+        for (label, file_source) in files {
+            // errors are reported with the line in the file where they occur
+            parse_style_file(file_source).map_err(|e| match e {
+                AppError::ParseError(message) => AppError::ParseError(format!("{}: {}", label, message)),
+                e => e,
+            })?;
+
+            let definitions = scan_definitions(file_source);
+            let mut redefined = Vec::new();
+            for definition in &definitions {
+                match defined_in.insert(definition.name, label) {
+                    Some(previous_label) if previous_label != label => {
+                        let line = file_source[.. definition.span.start].matches('\n').count() + 1;
+                        warnings.push(format!("{} (line {}) redefines '{}' from {}", label, line, definition.name, previous_label));
+                        redefined.push(definition.name);
+                    },
+                    _ => (),
+                }
+            }
+            if !redefined.is_empty() {
+                source = remove_definitions(&source, |name| redefined.contains(&name));
+            }
+            source.push_str(file_source);
+            source.push('\n');
+        }
+
+        let collection = CommandCollection::parse(&source).map_err(|e| AppError::ParseError(format!("{}", e)))?;
+        Ok((Self { source, collection }, warnings))
+    }
+
+    /// Parses a formula which may start with definitions of its own, e.g. '\newcommand{\R}{\mathbb{R}} \R^n'.
+    /// These definitions apply to this formula only ; they take precedence over custom commands of the same name.
+    pub fn parse_formula(&self, formula : &str) -> AppResult<Vec<ParseNode>> {
+        let (definitions, body_start) = split_definitions(formula)?;
+        let body = &formula[body_start ..];
+        let nodes = if definitions.is_empty() {
+            parse_with_custom_commands(body, &self.collection)
+        }
+        else {
+            parse_with_custom_commands(body, &self.extended_with(&definitions)?)
+        };
+        nodes.map_err(|e| AppError::ParseError(format!("{}", e)))
+    }
+
+    /// Parses the source of the custom commands again, with the definitions added
+    fn extended_with(&self, definitions : &[Definition]) -> AppResult<CommandCollection> {
+        let mut source = remove_definitions(&self.source, |name| definitions.iter().any(|definition| definition.name == name));
+        for definition in definitions {
+            source.push('\n');
+            source.push_str(&definition.to_newcommand());
+        }
+        CommandCollection::parse(&source).map_err(|e| AppError::ParseError(format!("{}", e)))
+    }
+}
+
+/// Parses the content of a style file into a collection of custom commands.
+/// On failure, the error message indicates the line where the faulty definition starts.
+pub fn parse_style_file(source : &str) -> AppResult<CommandCollection> {
+    match CommandCollection::parse(source) {
+        Ok(custom_cmd) => Ok(custom_cmd),
+        Err(e) => {
+            let message = e.to_string();
+            match locate_error(source) {
+                Some(line) => Err(AppError::ParseError(format!("{} (line {})", message, line))),
+                None       => Err(AppError::ParseError(message)),
+            }
+        },
+    }
+}
+
+/// Finds the line (1-based) where the first definition which fails to parse starts.
+/// We parse longer and longer prefixes of the file, cut at lines where all braces are closed ;
+/// the culprit starts right after the longest prefix which parses successfully.
+fn locate_error(source : &str) -> Option<usize> {
+    let mut depth : i64 = 0;
+    let mut chunk_start_line = 1;
+    let mut prefix_end = 0;
+
+    for (index, line) in source.split_inclusive('\n').enumerate() {
+        depth += brace_balance(line);
+        prefix_end += line.len();
+        if depth != 0 {
+            continue;
+        }
+        if CommandCollection::parse(&source[.. prefix_end]).is_err() {
+            return Some(chunk_start_line);
+        }
+        chunk_start_line = index + 2;
+    }
+
+    // The last definition is left unclosed
+    if depth != 0 { Some(chunk_start_line) } else { None }
+}
+
+fn brace_balance(line : &str) -> i64 {
+    let mut balance = 0;
+    let mut chars = line.chars();
+    while let Some(character) = chars.next() {
+        match character {
+            // escaped characters, e.g. '\{', don't count
+            '\\' => { chars.next(); },
+            // rest of the line is a comment
+            '%'  => break,
+            '{'  => balance += 1,
+            '}'  => balance -= 1,
+            _    => (),
+        }
+    }
+    balance
+}
+
+
+
+/// A definition of a custom command, e.g. '\newcommand{\R}{\mathbb{R}}' or '\def\abs#1{\left|#1\right|}'
+pub(crate) struct Definition<'a> {
+    /// Name of the command, with its backslash, e.g. '\R'
+    pub name      : &'a str,
+    pub arguments : usize,
+    pub body      : &'a str,
+    /// Position of the whole definition in the source
+    pub span      : Range<usize>,
+}
+
+impl Definition<'_> {
+    /// The definition in the syntax of style files
+    fn to_newcommand(&self) -> String {
+        match self.arguments {
+            0         => format!("\\newcommand{{{}}}{{{}}}", self.name, self.body),
+            arguments => format!("\\newcommand{{{}}}[{}]{{{}}}", self.name, arguments, self.body),
+        }
+    }
+}
+
+const DEFINITION_KEYWORDS : &[&str] = &["\\newcommand", "\\renewcommand", "\\def"];
+
+/// Splits the definitions at the start of a formula from the rest of the formula.
+/// Returns the definitions and the position where the rest of the formula starts.
+pub(crate) fn split_definitions(formula : &str) -> AppResult<(Vec<Definition<'_>>, usize)> {
+    let mut definitions = Vec::new();
+    let mut position = 0;
+    loop {
+        position = skip_whitespace(formula, position);
+        match read_definition(formula, position) {
+            Some(definition) => {
+                let definition = definition?;
+                position = definition.span.end;
+                definitions.push(definition);
+            },
+            None => return Ok((definitions, position)),
+        }
+    }
+}
+
+/// All the definitions of a style file which can be read ; comments are skipped
+pub(crate) fn scan_definitions(source : &str) -> Vec<Definition<'_>> {
+    let mut definitions = Vec::new();
+    let mut position = 0;
+    while let Some(offset) = source[position ..].find(['\\', '%']) {
+        position += offset;
+        if source[position ..].starts_with('%') {
+            position = source[position ..].find('\n').map(|end| position + end).unwrap_or(source.len());
+            continue;
+        }
+        match read_definition(source, position) {
+            Some(Ok(definition)) => {
+                position = definition.span.end;
+                definitions.push(definition);
+            },
+            // skip the backslash and the escaped character, e.g. '\%'
+            _ => position += 1 + source[position + 1 ..].chars().next().map(char::len_utf8).unwrap_or(0),
+        }
+    }
+    definitions
+}
+
+/// Removes from a style file the definitions of the commands whose name satisfies 'predicate' ; the rest of the file is left untouched
+fn remove_definitions<P : Fn(&str) -> bool>(source : &str, predicate : P) -> String {
+    let mut kept = String::with_capacity(source.len());
+    let mut copied = 0;
+    for definition in scan_definitions(source) {
+        if predicate(definition.name) {
+            kept.push_str(&source[copied .. definition.span.start]);
+            copied = definition.span.end;
+        }
+    }
+    kept.push_str(&source[copied ..]);
+    kept
+}
+
+/// Reads the definition starting at 'start' ; returns None if there is no definition there
+fn read_definition(source : &str, start : usize) -> Option<AppResult<Definition<'_>>> {
+    let rest = &source[start ..];
+    let keyword = DEFINITION_KEYWORDS.iter().find(|keyword| {
+        // e.g. '\definecolor' is not '\def'
+        rest.starts_with(*keyword) && !rest[keyword.len() ..].starts_with(|character : char| character.is_ascii_alphabetic())
+    })?;
+    let position = start + keyword.len();
+    let definition = if *keyword == "\\def" { read_def(source, start, position) } else { read_newcommand(source, start, position) };
+    Some(definition.ok_or_else(|| {
+        let excerpt : String = rest.chars().take(40).collect();
+        AppError::ParseError(format!("Malformed definition: {}", excerpt))
+    }))
+}
+
+/// Reads the rest of '\newcommand{\name}[arguments]{body}' ; braces around the name are optional
+fn read_newcommand(source : &str, start : usize, position : usize) -> Option<Definition<'_>> {
+    let mut position = skip_whitespace(source, position);
+    // starred variant, e.g. '\newcommand*'
+    if source[position ..].starts_with('*') {
+        position = skip_whitespace(source, position + 1);
+    }
+
+    let name = if source[position ..].starts_with('{') {
+        let (inner, end) = read_group(source, position)?;
+        position = end;
+        let inner = inner.trim();
+        let (name, name_end) = read_command_name(inner, 0)?;
+        if name_end != inner.len() { return None; }
+        name
+    }
+    else {
+        let (name, end) = read_command_name(source, position)?;
+        position = end;
+        name
+    };
+
+    position = skip_whitespace(source, position);
+    let mut arguments = 0;
+    if source[position ..].starts_with('[') {
+        let end = position + source[position ..].find(']')?;
+        arguments = source[position + 1 .. end].trim().parse().ok()?;
+        position = skip_whitespace(source, end + 1);
+        // default values of optional arguments are not supported
+        if source[position ..].starts_with('[') { return None; }
+    }
+
+    let (body, end) = read_group(source, position)?;
+    Some(Definition { name, arguments, body, span: start .. end })
+}
+
+/// Reads the rest of '\def\name#1#2{body}'
+fn read_def(source : &str, start : usize, position : usize) -> Option<Definition<'_>> {
+    let position = skip_whitespace(source, position);
+    let (name, mut position) = read_command_name(source, position)?;
+
+    let mut arguments = 0;
+    while let Some(parameter) = source[position ..].strip_prefix('#') {
+        // parameters are numbered in order
+        if !parameter.starts_with(char::from_digit(arguments + 1, 10)?) { return None; }
+        arguments += 1;
+        position += 2;
+    }
+
+    let (body, end) = read_group(source, position)?;
+    Some(Definition { name, arguments: arguments as usize, body, span: start .. end })
+}
+
+/// Reads a command name, e.g. '\alpha' or '\{', at 'position' ; returns the name and where it ends
+fn read_command_name(source : &str, position : usize) -> Option<(&str, usize)> {
+    let after_backslash = source[position ..].strip_prefix('\\')?;
+    let length = match after_backslash.find(|character : char| !character.is_ascii_alphabetic()) {
+        Some(0) => after_backslash.chars().next()?.len_utf8(),
+        Some(length) => length,
+        None if after_backslash.is_empty() => return None,
+        None => after_backslash.len(),
+    };
+    let end = position + 1 + length;
+    Some((&source[position .. end], end))
+}
+
+/// Reads a group in braces at 'position' ; returns the content of the group and where the group ends
+fn read_group(source : &str, position : usize) -> Option<(&str, usize)> {
+    if !source[position ..].starts_with('{') { return None; }
+    let mut depth = 0;
+    let mut characters = source[position ..].char_indices();
+    while let Some((offset, character)) = characters.next() {
+        match character {
+            // escaped characters, e.g. '\{', don't count
+            '\\' => { characters.next(); },
+            '{'  => depth += 1,
+            '}'  => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((&source[position + 1 .. position + offset], position + offset + 1));
+                }
+            },
+            _    => (),
+        }
+    }
+    None
+}
+
+fn skip_whitespace(source : &str, position : usize) -> usize {
+    source.len() - source[position ..].trim_start().len()
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary<'a>(definitions : &[Definition<'a>]) -> Vec<(&'a str, usize, &'a str)> {
+        definitions.iter().map(|definition| (definition.name, definition.arguments, definition.body)).collect()
+    }
+
+    #[test]
+    fn definition_syntaxes() {
+        let formula = concat!(
+            r"\newcommand*{\R}{\mathbb{R}} ",
+            r"\newcommand\P[2]{P(#1 | #2)} ",
+            r"\renewcommand { \N } [ 1 ] {\mathbb{N}_{#1}} ",
+            r"\def\abs#1{\left|#1\right|} ",
+            r"\abs{x} \in \R",
+        );
+        let (definitions, body_start) = split_definitions(formula).unwrap();
+        assert_eq!(&formula[body_start ..], r"\abs{x} \in \R");
+        assert_eq!(summary(&definitions), [
+            (r"\R",   0, r"\mathbb{R}"),
+            (r"\P",   2, r"P(#1 | #2)"),
+            (r"\N",   1, r"\mathbb{N}_{#1}"),
+            (r"\abs", 1, r"\left|#1\right|"),
+        ]);
+        assert_eq!(definitions[1].to_newcommand(), r"\newcommand{\P}[2]{P(#1 | #2)}");
+    }
+
+    #[test]
+    fn formula_without_definitions() {
+        let formula = r"\definecolor{x}{rgb}{1,0,0} \default \R";
+        let (definitions, body_start) = split_definitions(formula).unwrap();
+        assert!(definitions.is_empty());
+        assert_eq!(body_start, 0);
+    }
+
+    #[test]
+    fn malformed_definitions() {
+        for formula in [
+            r"\newcommand{\R}{\mathbb{R} x",
+            r"\newcommand{\R x}{y}",
+            r"\newcommand{\R}[1][a]{#1}",
+            r"\def\f#2{#2}",
+            r"\def{x}",
+        ] {
+            match split_definitions(formula) {
+                Err(AppError::ParseError(message)) => assert!(message.starts_with("Malformed definition: "), "{}", message),
+                _ => panic!("{} should be malformed", formula),
+            }
+        }
+    }
+
+    #[test]
+    fn comments_in_style_files() {
+        let source = concat!(
+            "% \\newcommand{\\A}{a}\n",
+            "\\newcommand{\\B}{b \\% c} % \\newcommand{\\C}{c}\n",
+            "\\%\\newcommand{\\D}{d}\n",
+            "\\definecolor{red}{rgb}{1,0,0}\n",
+        );
+        let definitions = scan_definitions(source);
+        assert_eq!(summary(&definitions), [(r"\B", 0, r"b \% c"), (r"\D", 0, "d")]);
+        assert_eq!(&source[definitions[0].span.clone()], r"\newcommand{\B}{b \% c}");
+    }
+
+    #[test]
+    fn removed_definitions() {
+        let source = "\\newcommand{\\A}{a}\n\\def\\B{b}\n\\newcommand{\\C}{c}";
+        assert_eq!(remove_definitions(source, |name| name != r"\A"), "\\newcommand{\\A}{a}\n\n");
+    }
+
+    #[test]
+    fn error_lines() {
+        assert_eq!(brace_balance(r"{ \{ } % {"), 0);
+        assert_eq!(brace_balance(r"\newcommand{\R}{"), 1);
+        let source = "\\newcommand{\\A}{a}\n\\newcommand{\\B}{\n  b\n}\n\\newcommand{\\C}{c\n\\newcommand{\\D}{d}\n";
+        assert_eq!(locate_error(source), Some(5));
+        assert_eq!(locate_error("\\newcommand{\\A}{a}\n\\newcommand{\\B}{b}\n"), None);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn layered_redefinitions() {
+        let (custom_commands, warnings) = CustomCommands::layered([
+            ("base.sty", "\\newcommand{\\R}{\\mathbb{R}}\n\\newcommand{\\N}{\\mathbb{N}}\n"),
+            ("mine.sty", "\\newcommand{\\Z}{\\mathbb{Z}}\n\\renewcommand{\\R}{\\mathbf{R}}\n"),
+        ]).unwrap();
+        assert_eq!(warnings, [r"mine.sty (line 2) redefines '\R' from base.sty"]);
+        let definitions = scan_definitions(&custom_commands.source);
+        assert_eq!(summary(&definitions), [
+            (r"\N", 0, r"\mathbb{N}"),
+            (r"\Z", 0, r"\mathbb{Z}"),
+            (r"\R", 0, r"\mathbf{R}"),
+        ]);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn layered_errors_name_the_file() {
+        match CustomCommands::layered([("a.sty", "\\newcommand{\\A}{a}\n"), ("b.sty", "\\newcommand{\\B}{b\n")]) {
+            Err(AppError::ParseError(message)) => assert!(message.starts_with("b.sty: ") && message.ends_with("(line 1)"), "{}", message),
+            _ => panic!("expected a parse error"),
+        }
+    }
+}
@@ -14,18 +14,20 @@ fn main() {
 mod desktop {
     use gtk4::prelude::{ApplicationExt, ActionMapExt, ApplicationExtManual};
     use gtk4::prelude::{GtkApplicationExt, GtkWindowExt};
-    use rex::font::backend::ttf_parser::TtfMathFont;
 
     use gtk4::gio::SimpleAction;
     use gtk4::glib::clone;
     use gtk4::glib;
     use gtk4::Application;
-    use maths_preview::error::AppResult;
     use maths_preview::desktop::ui::build_ui;
-    use maths_preview::desktop::app::AppContext;
+    use maths_preview::desktop::app::{AppContext, load_font};
     use maths_preview::desktop::cli;
+    use maths_preview::desktop::headless;
 
     pub fn main() {
+        if let Some(exit_code) = headless::run_subcommand() {
+            std::process::exit(exit_code);
+        }
         
         let app_context = AppContext::default();
 
@@ -43,7 +45,7 @@ mod desktop {
                     cli::handle_options(&app_context, option)
         }));
         application.connect_activate(clone!(#[strong] app_context, move |app| 
-            match load_font(app_context.math_font.get()) {
+            match load_font(app_context.math_font.get(), app_context.face_index.get()) {
                 Ok(font) => build_ui(app, font, app_context.clone()),
                 Err(e)   => {
                     eprintln!("{}", e);
@@ -68,11 +70,4 @@ mod desktop {
 
 
 
-    fn load_font<'a>(file : &'a [u8]) -> AppResult<TtfMathFont<'a>> {
-        let font = ttf_parser::Face::parse(file, 0)?;
-        Ok(TtfMathFont::new(font)?)
-    }
-
-
-
 }
\ No newline at end of file
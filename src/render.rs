@@ -6,10 +6,11 @@ use rex::font::backend::ttf_parser::TtfMathFont;
 use rex::cairo::CairoBackend;
 use rex::font::common::GlyphId;
 use std::collections::{HashMap, HashSet};
-use rex::{font::MathFont, layout::engine::LayoutBuilder, parser::{macros::CommandCollection, parse_with_custom_commands}, Renderer};
+use rex::{font::MathFont, layout::engine::LayoutBuilder, parser::ParseNode, Renderer};
 use serde::Serialize;
 
-use crate::{geometry::{Metrics, BBox}, error::{AppResult, AppError}};
+use crate::{geometry::{Metrics, BBox}, error::AppResult};
+use crate::macros::{split_definitions, CustomCommands};
 use crate::glyph_to_character::collect_chars;
 use crate::svg::GivesOutline;
 
@@ -32,7 +33,7 @@ pub fn draw_formula<'a, F, B>(
     font : &F, 
     font_size : f64, 
     canvas_size : Option<(f64, f64)>,
-    custom_cmd : &CommandCollection,
+    custom_cmd : &CustomCommands,
 ) -> AppResult<()> 
 where 
     F : MathFont,
@@ -68,14 +69,18 @@ where
     Ok(())
 }
 
+/// Renders the formula to SVG, laid out in 'style' if given ; the SVG keeps the formula as written in its metadata.
 pub fn render_svg<F: MathFont + GlyphAsTextUtilities + GivesOutline>(
     formula : &str, 
+    style : Option<FormulaStyle>,
     font : &F, 
     font_size : f64, 
-    custom_cmd : &CommandCollection, 
-    glyph_as_text : bool
+    custom_cmd : &CustomCommands, 
+    glyph_as_text : bool,
+    color : rex::RGBA,
 ) -> AppResult<(Metrics, String)> {
-    let nodes = parse_with_custom_commands(formula, custom_cmd).map_err(|e| AppError::ParseError(format!("{}", e)))?;
+    let styled_formula = style.map(|style| style.apply(formula));
+    let nodes = custom_cmd.parse_formula(styled_formula.as_deref().unwrap_or(formula))?;
 
 
 
@@ -107,21 +112,12 @@ pub fn render_svg<F: MathFont + GlyphAsTextUtilities + GivesOutline>(
     // For text-as-text rendering, we need to construct the glyph to char oracle
 
     let mut svg = crate::svg::SvgContext::new();
+    svg.set_default_color(color);
+    svg.set_source(formula);
     if glyph_as_text {
-        let mut char_set = HashSet::new();
-        for node in nodes {
-            collect_chars(&node, &mut char_set);
-        }
-        let glyph_to_char_table : HashMap<GlyphId, char> = 
-            char_set
-            .into_iter()
-            .map(|character| font.glyph_index(character).map(|glyph_id| (glyph_id, character)))
-            .flatten()
-            .collect()
-        ;
         let font_name = font.get_font_family_name();
         if let Some(font_name) = font_name {
-            svg.glyph_as_text(glyph_to_char_table, &font_name);
+            svg.glyph_as_text(glyph_to_char_table(&nodes, font), &font_name);
         }
     }
 
@@ -133,6 +129,56 @@ pub fn render_svg<F: MathFont + GlyphAsTextUtilities + GivesOutline>(
     Ok((formula_metrics, svg_string))
 }
 
+/// Builds the table from glyphs to the characters they render, for the characters occurring in the formula.
+pub fn glyph_to_char_table<F: MathFont>(nodes : &[ParseNode], font : &F) -> HashMap<GlyphId, char> {
+    let mut char_set = HashSet::new();
+    for node in nodes {
+        collect_chars(node, &mut char_set);
+    }
+    char_set
+        .into_iter()
+        .map(|character| font.glyph_index(character).map(|glyph_id| (glyph_id, character)))
+        .flatten()
+        .collect()
+}
+
+/// Math style in which a formula is typeset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormulaStyle {
+    /// Inline style, as in '$...$'
+    Text,
+    /// Display style, as in '$$...$$'
+    Display,
+}
+
+impl FormulaStyle {
+    pub fn from_name(name : &str) -> Option<Self> {
+        match name {
+            "text"    => Some(Self::Text),
+            "display" => Some(Self::Display),
+            _         => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Text    => "text",
+            Self::Display => "display",
+        }
+    }
+
+    /// Prefixes the formula with the command switching to this style.
+    /// The command goes after the definitions the formula may start with, cf 'CustomCommands::parse_formula'.
+    pub fn apply(self, formula : &str) -> String {
+        let body_start = split_definitions(formula).map(|(_, body_start)| body_start).unwrap_or(0);
+        let (definitions, body) = formula.split_at(body_start);
+        match self {
+            Self::Text    => format!("{}\\textstyle {}", definitions, body),
+            Self::Display => format!("{}\\displaystyle {}", definitions, body),
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct MetaInfo {
     pub metrics : Metrics,
@@ -140,8 +186,8 @@ pub struct MetaInfo {
 }
 
 
-pub fn layout_and_size<'f, T : MathFont>(font: &'f T, font_size : f64, formula: &str, custom_cmd : &CommandCollection) -> AppResult<(rex::layout::Layout<'f, T>, Metrics)> {
-    let parse_node = parse_with_custom_commands(formula, custom_cmd).map_err(|e| AppError::ParseError(format!("{}", e)))?;
+pub fn layout_and_size<'f, T : MathFont>(font: &'f T, font_size : f64, formula: &str, custom_cmd : &CustomCommands) -> AppResult<(rex::layout::Layout<'f, T>, Metrics)> {
+    let parse_node = custom_cmd.parse_formula(formula)?;
 
     // Create node
     let layout = 
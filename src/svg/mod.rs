@@ -12,20 +12,36 @@ struct TextAsText {
 pub struct SvgContext {
     content : String,
     color_stack : Vec<rex::RGBA>,
+    default_color : rex::RGBA,
+    source : Option<String>,
     glyph_as_text : Option<TextAsText>,
 }
 
 impl SvgContext {
-    const DEFAULT_COLOR : rex::RGBA = rex::RGBA(0x00, 0x00, 0x00, 0xff);
+    pub const DEFAULT_COLOR : rex::RGBA = rex::RGBA(0x00, 0x00, 0x00, 0xff);
 
     pub fn new() -> Self { 
-        Self { content : String::new(), color_stack: Vec::new(), glyph_as_text : None } 
+        Self { content : String::new(), color_stack: Vec::new(), default_color : Self::DEFAULT_COLOR, source : None, glyph_as_text : None } 
+    }
+
+    /// Sets the color of the parts of the formula which aren't colored with '\color'
+    pub fn set_default_color(&mut self, color : rex::RGBA) {
+        self.default_color = color;
+    }
+
+    /// Embeds the source of the formula in the metadata of the SVG, so that the formula can be edited again
+    pub fn set_source(&mut self, source : &str) {
+        self.source = Some(source.to_string());
     }
 
     pub fn finalize(self, x : f64, y : f64, width : f64, height : f64) -> String {
-        format!(r#"<svg viewBox="{} {} {} {}">{}</svg>"#, 
+        let metadata = self.source
+            .map(|source| format!("<metadata>{}</metadata>", escape_xml(&source)))
+            .unwrap_or_default();
+        format!(r#"<svg viewBox="{} {} {} {}">{}{}</svg>"#, 
             x, y,
             width, height, 
+            metadata,
             self.content
         )
     }
@@ -43,12 +59,16 @@ impl SvgContext {
     }
 
     fn current_color(&self) -> rex::RGBA {
-        self.color_stack.last().cloned().unwrap_or(Self::DEFAULT_COLOR)
+        self.color_stack.last().cloned().unwrap_or(self.default_color)
     }
 
 
 }
 
+fn escape_xml(text : &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
 impl<T : GivesOutline> Backend<T> for SvgContext {}
 
 
@@ -56,8 +76,9 @@ impl GraphicsBackend for SvgContext {
     fn rule(&mut self, pos: Cursor, width: f64, height: f64) {
         let Cursor { x, y } = pos;
         self.content.push_str(&format!(
-            r#"<rect x="{}" y="{}" width="{}" height="{}" />"#,
-            x, y, width, height
+            r#"<rect x="{}" y="{}" width="{}" height="{}" {} />"#,
+            x, y, width, height,
+            to_xml_color(self.current_color()),
             ));
     }
 
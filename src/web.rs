@@ -1,20 +1,24 @@
+#[cfg(feature = "dom")]
 mod canvas;
+#[cfg(feature = "dom")]
+mod dom;
 mod owned_math_font;
+mod raster;
 
 
 
-use canvas::{CanvasContext, OffscreenCanvasContext};
-use owned_math_font::TtfMathFont;
-use rex::{parser::parse, layout::engine::LayoutBuilder, Renderer};
-use rex::parser::macros::CommandCollection;
-use web_sys::{CanvasRenderingContext2d, OffscreenCanvasRenderingContext2d,};
+use raster::RasterContext;
+use owned_math_font::{TtfMathFont, LoadedFont};
+use rex::Renderer;
 use wasm_bindgen::prelude::*;
-use owned_ttf_parser::{OwnedFace, AsFaceRef};
-use crate::error::{AppError, AppResult};
+use owned_ttf_parser::OwnedFace;
+use crate::error::AppResult;
+use crate::font_loading::{decompress, pick_face_index};
+use crate::macros::CustomCommands;
 
-use crate::geometry::{BBox, Metrics};
 use crate::svg::SvgContext;
-use crate::render::{render_svg, scale_and_center, layout_and_size, render_layout};
+use crate::render::{render_svg, layout_and_size, FormulaStyle, MetaInfo};
+use serde::Serialize;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -24,26 +28,86 @@ use crate::render::{render_svg, scale_and_center, layout_and_size, render_layout
 
 
 
-const FONT_FILE : &[u8] = include_bytes!("../resources/LibertinusMath-Regular.otf");
-// const FONT_FILE : &[u8] = include_bytes!("../resources/LibertinusMath-Regular.woff2");
+const FONT_FILE : &[u8] = include_bytes!("../resources/LibertinusMath-Regular.woff2");
 
 
 
+/// Font and settings used for rendering.
+/// The context owns its font ; the font is released when the context is freed from JS (with 'free()').
 #[wasm_bindgen]
 pub struct Context {
-    face : *const OwnedFace,
+    font : LoadedFont,
     glyph_as_text: bool,
     font_size: f64,
+    custom_cmd: CustomCommands,
+    color: rex::RGBA,
+    /// If None, formulas are laid out in the default style of the layout engine
+    style: Option<FormulaStyle>,
+    scale_policy: ScalePolicy,
+    /// Number of device pixels per CSS pixel, i.e. 'window.devicePixelRatio' ; only used for on-screen canvases
+    device_pixel_ratio: f64,
+}
+
+/// How formulas are scaled when rendered on a canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalePolicy {
+    /// Zoom in or out so that the formula fills the canvas
+    Fit,
+    /// Render at the font size of the context, even if the formula overflows
+    Fixed,
+    /// Render at the font size of the context, unless the formula overflows, in which case zoom out
+    Shrink,
+}
+
+impl ScalePolicy {
+    fn from_name(name : &str) -> Option<Self> {
+        match name {
+            "fit"    => Some(Self::Fit),
+            "fixed"  => Some(Self::Fixed),
+            "shrink" => Some(Self::Shrink),
+            _        => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Fit    => "fit",
+            Self::Fixed  => "fixed",
+            Self::Shrink => "shrink",
+        }
+    }
 }
 
 impl Context {
-    pub fn font<'a>(& 'a self) -> & 'a OwnedFace {
-        unsafe { self.face.as_ref::<'a>().unwrap() }
+    pub fn font(&self) -> &OwnedFace {
+        self.font.borrow_owner()
+    }
+
+    pub fn math_font(&self) -> &TtfMathFont<'_, '_> {
+        self.font.math_font()
+    }
+
+    pub fn new(font : LoadedFont) -> Self {
+        Self { 
+            font, 
+            font_size: FONT_SIZE, 
+            glyph_as_text: false, 
+            custom_cmd: CustomCommands::default(), 
+            color: SvgContext::DEFAULT_COLOR,
+            style: None,
+            scale_policy: ScalePolicy::Fit,
+            device_pixel_ratio: 1.,
+        }
     }
 
-    pub fn new(value : Box<OwnedFace>) -> Self {
-        Self { face: Box::leak(value), font_size: FONT_SIZE, glyph_as_text: false, }
+    /// The formula as it is handed to the parser, i.e. with the style command prepended if any.
+    fn styled_formula(&self, formula : &str) -> String {
+        match self.style {
+            Some(style) => style.apply(formula),
+            None        => formula.to_string(),
+        }
     }
+
 }
 
 #[wasm_bindgen]
@@ -60,6 +124,62 @@ impl Context {
         }
     }
 
+    /// Sets the custom commands available in formulas from the content of a style file.
+    /// If the style file fails to parse, the previous custom commands are kept.
+    pub fn set_style_file(&mut self, sty_file : &str) -> Result<(), JsError> {
+        self.custom_cmd = CustomCommands::parse(sty_file).map_err(|e| JsError::new(&e.to_string()))?;
+        Ok(())
+    }
+
+    /// Sets the color of formulas, as a CSS hex color: '#rrggbb' or '#rrggbbaa'.
+    pub fn set_color(&mut self, color : &str) -> Result<(), JsError> {
+        self.color = parse_hex_color(color).ok_or_else(|| JsError::new(&format!("Invalid color: {:?}", color)))?;
+        Ok(())
+    }
+
+    /// Sets the style of formulas: "text", "display" or "" (the default style of the layout engine).
+    pub fn set_style(&mut self, style : &str) -> Result<(), JsError> {
+        self.style = match style {
+            "" => None,
+            _  => Some(FormulaStyle::from_name(style).ok_or_else(|| JsError::new(&format!("Unknown style: {:?}", style)))?),
+        };
+        Ok(())
+    }
+
+    /// Sets how formulas are scaled on canvases: "fit", "fixed" or "shrink".
+    pub fn set_scale_policy(&mut self, scale_policy : &str) -> Result<(), JsError> {
+        self.scale_policy = ScalePolicy::from_name(scale_policy).ok_or_else(|| JsError::new(&format!("Unknown scale policy: {:?}", scale_policy)))?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn color(&self) -> String {
+        let rex::RGBA(r, g, b, a) = self.color;
+        format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn style(&self) -> String {
+        self.style.map(FormulaStyle::name).unwrap_or_default().to_string()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn scale_policy(&self) -> String {
+        self.scale_policy.name().to_string()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn device_pixel_ratio(&self) -> f64 {
+        self.device_pixel_ratio
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_device_pixel_ratio(&mut self, device_pixel_ratio : f64) {
+        if device_pixel_ratio > 0. {
+            self.device_pixel_ratio = device_pixel_ratio;
+        }
+    }
+
     #[wasm_bindgen(getter)]
     pub fn glyph_as_text(&self) -> bool {
         self.glyph_as_text
@@ -73,82 +193,77 @@ impl Context {
 
 #[wasm_bindgen]
 pub fn init_font() -> Context {
-    let font = Box::new(owned_ttf_parser::OwnedFace::from_vec(FONT_FILE.to_vec(), 0).unwrap());
-    Context::new(font)
+    load_font(FONT_FILE, None).unwrap()
 }
 
-const FONT_SIZE : f64 = 10.;
-
-
+/// Creates a context from a user-supplied font file (OpenType, font collection, WOFF or WOFF2).
+/// If 'face_index' is not given, the first face with a MATH table is used.
 #[wasm_bindgen]
-pub fn render_formula_to_offscreen_canvas_js_err(
-    context    : &Context,
-    formula    : &str,
-    // since we can't know what size the formula will be prior to calling 'layout'
-    // the canvas is created by a JS function which takes two arguments
-    make_new_canvas : &js_sys::Function,
-) -> Result<(), String> {
-    render_formula_to_offscreen_canvas(context, formula, make_new_canvas).map_err(|e| {
-        e.to_string()
-    })
+pub fn init_font_from_bytes(font_file : &[u8], face_index : Option<u32>) -> Result<Context, JsError> {
+    load_font(font_file, face_index).map_err(|e| JsError::new(&e.to_string()))
 }
 
-fn render_formula_to_offscreen_canvas(
-    context    : &Context,
-    formula    : &str,
-    canvas_with_size : &js_sys::Function,
-)  -> Result<(), AppError> {
-    const PNG_FONT_SIZE : f64 = 300.;
-    let font = context.font();
-    let math_font  = TtfMathFont::new(font.as_face_ref()).unwrap();
-
-    let (layout, formula_metrics) = layout_and_size(&math_font, PNG_FONT_SIZE, formula, &CommandCollection::default())?;
-
-    let width  = formula_metrics.bbox.width();
-    let height = formula_metrics.bbox.height();
-    let canvas_context : OffscreenCanvasRenderingContext2d = 
-        canvas_with_size
-        .call2(&JsValue::NULL, &JsValue::from_f64(width), &JsValue::from_f64(height),)
-        .unwrap()
-        .unchecked_into()
-    ;
-
-
-    canvas_context.translate(0., height + formula_metrics.baseline).unwrap();
-
-    let mut context = OffscreenCanvasContext::new(&canvas_context);
-    Renderer::new().render(&layout, &mut context);
-
+fn load_font(font_file : &[u8], face_index : Option<u32>) -> AppResult<Context> {
+    let font_file = decompress(font_file)?.into_owned();
+    let face_index = pick_face_index(&font_file, face_index);
+    let font = OwnedFace::from_vec(font_file, face_index)?;
+    Ok(Context::new(LoadedFont::load(font)?))
+}
 
-    Ok(())
+const FONT_SIZE : f64 = 10.;
+/// Resolution of PNG exports: a formula with font size 10 is rendered with glyphs 300 pixels tall.
+const PNG_PIXELS_PER_POINT : f64 = 30.;
+
+/// Parses '#rrggbb' or '#rrggbbaa'
+fn parse_hex_color(color : &str) -> Option<rex::RGBA> {
+    let hex = color.strip_prefix('#')?;
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+        return None;
+    }
+    let component = |i : usize| u8::from_str_radix(&hex[2 * i .. 2 * i + 2], 16).ok();
+    let alpha = if hex.len() == 8 { component(3)? } else { 0xff };
+    Some(rex::RGBA(component(0)?, component(1)?, component(2)?, alpha))
 }
 
+
+/// Renders the formula to a PNG file, returned as a 'Uint8Array'.
+/// Rendering is done in Rust, so this works where no canvas is available (e.g. Web Workers, Node).
+/// Glyphs are always rendered as curves, even if 'glyph_as_text' is set.
 #[wasm_bindgen]
-pub fn render_formula_to_canvas_js_err(
+pub fn render_formula_to_png(
     context : &Context,
-    formula : &str, 
-    canvas  : &CanvasRenderingContext2d
-) -> Result<(), String> {
-    render_formula_to_canvas(context, formula, canvas).map_err(|e| {
-        e.to_string()
-    })
+    formula : &str,
+) -> Result<Vec<u8>, JsError> {
+    let formula = context.styled_formula(formula);
+    // exports have the same size whatever the screen: the device pixel ratio only applies to on-screen canvases
+    let font_size = context.font_size * PNG_PIXELS_PER_POINT;
+
+    let (layout, formula_metrics) = layout_and_size(context.math_font(), font_size, &formula, &context.custom_cmd)
+        .map_err(|e| JsError::new(&e.to_string()))?;
+
+    let mut backend = RasterContext::new(&formula_metrics, context.color)
+        .map_err(|e| JsError::new(&e.to_string()))?;
+    Renderer::new().render(&layout, &mut backend);
+    Ok(backend.finalize().encode_png())
 }
 
 
+
 #[wasm_bindgen]
 pub fn render_formula_to_svg(
     context : &Context,
     formula : &str, 
 ) -> Result<String, String> {
-    let font = context.font();
-    let math_font  = TtfMathFont::new(font.as_face_ref()).unwrap();
+    let math_font = context.math_font();
 
     let svg_render_result = render_svg(
         formula,
-        &math_font,
+        context.style,
+        math_font,
         context.font_size,
-        &CommandCollection::default(),
+        &context.custom_cmd,
         context.glyph_as_text,
+        context.color,
     );
     match svg_render_result {
         Ok((_, svg_string)) => Ok(svg_string),
@@ -156,28 +271,39 @@ pub fn render_formula_to_svg(
     }
 }
 
+/// Result of 'render_formula_to_svg_with_metainfo', passed to JS as an object 
+/// '{ svg, metainfo : { metrics : { bbox, baseline, font_size }, formula } }'
+#[derive(Debug, Serialize)]
+struct SvgRender {
+    svg      : String,
+    metainfo : MetaInfo,
+}
 
-fn render_formula_to_canvas(
+/// Like 'render_formula_to_svg', but also returns the metrics of the formula (e.g. to align the SVG with the baseline of the surrounding text).
+/// All measures are in SVG user units.
+#[wasm_bindgen]
+pub fn render_formula_to_svg_with_metainfo(
     context : &Context,
     formula : &str, 
-    canvas  : &CanvasRenderingContext2d
-) -> AppResult<()> {
-    let font = context.font();
-    let math_font  = TtfMathFont::new(font.as_face_ref()).unwrap();
-    let mut context = CanvasContext::new(canvas);
-    let canvas_size = get_canvas_size(&context);
-    context.rendering_context.clear_rect(0., 0., canvas_size.0, canvas_size.1);
-    let (layout, formula_metrics) = layout_and_size(&math_font, FONT_SIZE, formula, &CommandCollection::default())?;
-    render_layout(&mut context, Some(canvas_size), &formula_metrics, layout);
-    Ok(())
+) -> Result<JsValue, JsError> {
+    let (metrics, svg) = render_svg(
+        formula,
+        context.style,
+        context.math_font(),
+        context.font_size,
+        &context.custom_cmd,
+        context.glyph_as_text,
+        context.color,
+    ).map_err(|e| JsError::new(&e.to_string()))?;
+
+    let svg_render = SvgRender { 
+        svg, 
+        metainfo : MetaInfo { metrics, formula: formula.to_string() },
+    };
+    serde_wasm_bindgen::to_value(&svg_render).map_err(|e| JsError::new(&e.to_string()))
 }
 
-fn get_canvas_size(context: &CanvasContext) -> (f64, f64) {
-    let width  = context.rendering_context.canvas().unwrap().width() as f64;
-    let height = context.rendering_context.canvas().unwrap().height() as f64;
-    let canvas_size = (width, height,);
-    canvas_size
-}
+
 
 
 
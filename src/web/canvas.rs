@@ -1,37 +1,110 @@
 use rex::{Backend, font::common::GlyphId, GraphicsBackend, FontBackend};
 use owned_ttf_parser::OutlineBuilder;
+use wasm_bindgen::JsValue;
 use web_sys::{CanvasRenderingContext2d, CanvasWindingRule, OffscreenCanvasRenderingContext2d};
 
 use crate::web::AppResult;
 use crate::render::RenderingView;
 use super::owned_math_font::{TtfMathFont, into};
+use std::collections::HashMap;
+
+
+pub const DEFAULT_COLOR : rex::RGBA = rex::RGBA(0x00, 0x00, 0x00, 0xff);
+
+/// The drawing operations of a 2D canvas context which we need for rendering.
+/// 'CanvasRenderingContext2d' and 'OffscreenCanvasRenderingContext2d' have the same methods but share no trait in 'web_sys'.
+pub trait Canvas2d {
+    fn save(&self);
+    fn restore(&self);
+    fn translate(&self, x : f64, y : f64) -> Result<(), JsValue>;
+    fn scale(&self, sx : f64, sy : f64) -> Result<(), JsValue>;
+    fn begin_path(&self);
+    fn close_path(&self);
+    fn move_to(&self, x : f64, y : f64);
+    fn line_to(&self, x : f64, y : f64);
+    fn quadratic_curve_to(&self, x1 : f64, y1 : f64, x : f64, y : f64);
+    fn bezier_curve_to(&self, x1 : f64, y1 : f64, x2 : f64, y2 : f64, x : f64, y : f64);
+    fn rect(&self, x : f64, y : f64, width : f64, height : f64);
+    fn fill_with_canvas_winding_rule(&self, winding_rule : CanvasWindingRule);
+    fn set_fill_style_str(&self, style : &str);
+    fn set_font(&self, font : &str);
+    fn fill_text(&self, text : &str, x : f64, y : f64) -> Result<(), JsValue>;
+}
+
+macro_rules! impl_canvas_2d {
+    ($context:ty) => {
+        impl Canvas2d for $context {
+            fn save(&self) { <$context>::save(self) }
+            fn restore(&self) { <$context>::restore(self) }
+            fn translate(&self, x : f64, y : f64) -> Result<(), JsValue> { <$context>::translate(self, x, y) }
+            fn scale(&self, sx : f64, sy : f64) -> Result<(), JsValue> { <$context>::scale(self, sx, sy) }
+            fn begin_path(&self) { <$context>::begin_path(self) }
+            fn close_path(&self) { <$context>::close_path(self) }
+            fn move_to(&self, x : f64, y : f64) { <$context>::move_to(self, x, y) }
+            fn line_to(&self, x : f64, y : f64) { <$context>::line_to(self, x, y) }
+            fn quadratic_curve_to(&self, x1 : f64, y1 : f64, x : f64, y : f64) { <$context>::quadratic_curve_to(self, x1, y1, x, y) }
+            fn bezier_curve_to(&self, x1 : f64, y1 : f64, x2 : f64, y2 : f64, x : f64, y : f64) { <$context>::bezier_curve_to(self, x1, y1, x2, y2, x, y) }
+            fn rect(&self, x : f64, y : f64, width : f64, height : f64) { <$context>::rect(self, x, y, width, height) }
+            fn fill_with_canvas_winding_rule(&self, winding_rule : CanvasWindingRule) { <$context>::fill_with_canvas_winding_rule(self, winding_rule) }
+            fn set_fill_style_str(&self, style : &str) { <$context>::set_fill_style_str(self, style) }
+            fn set_font(&self, font : &str) { <$context>::set_font(self, font) }
+            fn fill_text(&self, text : &str, x : f64, y : f64) -> Result<(), JsValue> { <$context>::fill_text(self, text, x, y) }
+        }
+    };
+}
 
+impl_canvas_2d!(CanvasRenderingContext2d);
+impl_canvas_2d!(OffscreenCanvasRenderingContext2d);
 
 
 #[derive(Debug, Clone)]
-pub struct CanvasContext<'a> {
-    pub rendering_context: &'a CanvasRenderingContext2d,
+pub struct TextAsText {
+    glyph_to_char_table : HashMap<GlyphId, char>,
+    font_name : Box<str>,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct CanvasContext<'a, C = CanvasRenderingContext2d> {
+    pub rendering_context: &'a C,
     pub color_stack: Vec<rex::RGBA>,
+    pub default_color: rex::RGBA,
+    pub glyph_as_text: Option<TextAsText>,
 }
 
-impl<'a> CanvasContext<'a> {
-    pub fn new(rendering_context: &'a CanvasRenderingContext2d) -> Self {
+pub type OffscreenCanvasContext<'a> = CanvasContext<'a, OffscreenCanvasRenderingContext2d>;
+
+impl<'a, C : Canvas2d> CanvasContext<'a, C> {
+    pub fn new(rendering_context: &'a C) -> Self {
         Self {
             rendering_context,
             color_stack: Vec::new(),
+            default_color: DEFAULT_COLOR,
+            glyph_as_text: None,
         }
     }
+
+    /// Renders glyphs with 'fill_text' rather than as curves whenever the glyph to character table knows the glyph.
+    pub fn glyph_as_text(&mut self, glyph_to_char_table : HashMap<GlyphId, char>, font_name : &str) {
+        self.glyph_as_text = Some(TextAsText { glyph_to_char_table, font_name: font_name.into() });
+    }
+
+    fn current_color(&self) -> rex::RGBA {
+        self.color_stack.last().copied().unwrap_or(self.default_color)
+    }
 }
 
 
-impl<'a, 'b> Backend<TtfMathFont<'a, 'b>> for CanvasContext<'_> {}
+impl<'a, 'b, C : Canvas2d> Backend<TtfMathFont<'a, 'b>> for CanvasContext<'_, C> {}
 
-impl GraphicsBackend for CanvasContext<'_> {
+impl<C : Canvas2d> GraphicsBackend for CanvasContext<'_, C> {
     fn rule(&mut self, pos: rex::Cursor, width: f64, height: f64) {
         let canvas = self.rendering_context;
 
+        canvas.begin_path();
+        canvas.set_fill_style_str(&rgba_u8_to_hex_string(self.current_color()));
         canvas.rect(pos.x, pos.y, width, height);
-        canvas.fill();
+        canvas.fill_with_canvas_winding_rule(CanvasWindingRule::Nonzero);
     }
 
     fn begin_color(&mut self, color: rex::RGBA) {
@@ -44,54 +117,54 @@ impl GraphicsBackend for CanvasContext<'_> {
 }
 
 
-impl FontBackend<TtfMathFont<'_, '_>> for CanvasContext<'_> {
+impl<C : Canvas2d> FontBackend<TtfMathFont<'_, '_>> for CanvasContext<'_, C> {
     fn symbol(&mut self, pos: rex::Cursor, gid: GlyphId, scale: f64, font: &TtfMathFont<'_, '_>) {
         let canvas = self.rendering_context;
-        
+        canvas.set_fill_style_str(&rgba_u8_to_hex_string(self.current_color()));
+
+        if let Some(TextAsText { glyph_to_char_table, font_name }) = &self.glyph_as_text {
+            if let Some(character) = glyph_to_char_table.get(&gid) {
+                canvas.set_font(&format!("{}px \"{}\"", scale, font_name));
+                canvas.fill_text(&character.to_string(), pos.x, pos.y).unwrap();
+                return;
+            }
+        }
+
         canvas.save();
         canvas.translate(pos.x, pos.y).unwrap();
         canvas.scale(scale, -scale).unwrap();
         canvas.scale(font.font_matrix().sx.into(), font.font_matrix().sy.into(),).unwrap();
         canvas.begin_path();
 
-        if let Some(color) = self.color_stack.last() {
-            canvas.set_fill_style_str(&rgba_u8_to_hex_string(*color))
-        }
-
-        struct Builder<'a> { 
-            canvas : &'a CanvasRenderingContext2d,
+        struct Builder<'a, C> {
+            canvas : &'a C,
         }
 
-        impl<'a> Builder<'a> {
+        impl<C : Canvas2d> Builder<'_, C> {
             fn fill(self) {
-                self.canvas.fill_with_canvas_winding_rule(CanvasWindingRule::Evenodd);
                 self.canvas.close_path();
+                self.canvas.fill_with_canvas_winding_rule(CanvasWindingRule::Evenodd);
             }
         }
 
-        impl<'a> OutlineBuilder for Builder<'a> {
+        impl<C : Canvas2d> OutlineBuilder for Builder<'_, C> {
             fn move_to(&mut self, x: f32, y: f32) {
-                // eprintln!("move_to {:?} {:?}", x, y);
                 self.canvas.move_to(x.into(), y.into());
             }
 
             fn line_to(&mut self, x: f32, y: f32) {
-                // eprintln!("line_to {:?} {:?}", x, y);
                 self.canvas.line_to(x.into(), y.into());
             }
 
             fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
-                // eprintln!("quad_to  {:?} {:?} {:?} {:?}", x1, y1, x, y);
                 self.canvas.quadratic_curve_to(x1.into(), y1.into(), x.into(), y.into(),)
             }
 
             fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
-                // eprintln!("curve_to {:?} {:?} {:?} {:?} {:?} {:?}", x1, y1, x2, y2, x, y);
                 self.canvas.bezier_curve_to(x1.into(), y1.into(), x2.into(), y2.into(), x.into(), y.into(),)
             }
 
             fn close(&mut self) {
-                // eprintln!("close");
                 self.canvas.close_path();
             }
 
@@ -106,8 +179,7 @@ impl FontBackend<TtfMathFont<'_, '_>> for CanvasContext<'_> {
     }
 }
 
-#[cfg(target_arch = "wasm32")]
-impl RenderingView for CanvasContext<'_> {
+impl<C : Canvas2d> RenderingView for CanvasContext<'_, C> {
     fn save(&mut self) -> AppResult<()> {
         self.rendering_context.save();
         Ok(())
@@ -118,115 +190,18 @@ impl RenderingView for CanvasContext<'_> {
         Ok(())
     }
 
+    // 'web_sys' declares 'translate' and 'scale' as throwing, but browsers silently ignore invalid (non-finite) arguments
     fn translate(&mut self, x : f64, y : f64) -> AppResult<()> {
-        self.rendering_context.translate(x, y);
+        self.rendering_context.translate(x, y).ok();
         Ok(())
     }
 
     fn scale(&mut self, sx : f64, sy : f64) -> AppResult<()> {
-        self.rendering_context.scale(sx, sy);
+        self.rendering_context.scale(sx, sy).ok();
         Ok(())
     }
 }
 
-
-#[derive(Debug, Clone)]
-pub struct OffscreenCanvasContext<'a> {
-    pub rendering_context: &'a OffscreenCanvasRenderingContext2d,
-    pub color_stack: Vec<rex::RGBA>,
-}
-
-
-impl<'a> OffscreenCanvasContext<'a> {
-    pub fn new(rendering_context: &'a OffscreenCanvasRenderingContext2d) -> Self {
-        Self {
-            rendering_context,
-            color_stack: Vec::new(),
-        }
-    }
-}
-
-impl<'a, 'b> Backend<TtfMathFont<'a, 'b>> for OffscreenCanvasContext<'_> {}
-
-impl GraphicsBackend for OffscreenCanvasContext<'_> {
-    fn rule(&mut self, pos: rex::Cursor, width: f64, height: f64) {
-        let canvas = self.rendering_context;
-
-        canvas.rect(pos.x, pos.y, width, height);
-        canvas.fill();
-    }
-
-    fn begin_color(&mut self, color: rex::RGBA) {
-        self.color_stack.push(color);
-    }
-
-    fn end_color(&mut self) {
-        self.color_stack.pop();
-    }
-}
-
-
-impl FontBackend<TtfMathFont<'_, '_>> for OffscreenCanvasContext<'_> {
-    fn symbol(&mut self, pos: rex::Cursor, gid: GlyphId, scale: f64, font: &TtfMathFont<'_, '_>) {
-        let canvas = self.rendering_context;
-        
-        canvas.save();
-        canvas.translate(pos.x, pos.y).unwrap();
-        canvas.scale(scale, -scale).unwrap();
-        canvas.scale(font.font_matrix().sx.into(), font.font_matrix().sy.into(),).unwrap();
-        canvas.begin_path();
-
-        if let Some(color) = self.color_stack.last() {
-            canvas.set_fill_style_str(&rgba_u8_to_hex_string(*color))
-        }
-        struct Builder<'a> { 
-            canvas : &'a OffscreenCanvasRenderingContext2d,
-        }
-
-        impl<'a> Builder<'a> {
-            fn fill(self) {
-                self.canvas.fill_with_canvas_winding_rule(CanvasWindingRule::Evenodd);
-                self.canvas.close_path();
-            }
-        }
-
-        impl<'a> OutlineBuilder for Builder<'a> {
-            fn move_to(&mut self, x: f32, y: f32) {
-                // eprintln!("move_to {:?} {:?}", x, y);
-                self.canvas.move_to(x.into(), y.into());
-            }
-
-            fn line_to(&mut self, x: f32, y: f32) {
-                // eprintln!("line_to {:?} {:?}", x, y);
-                self.canvas.line_to(x.into(), y.into());
-            }
-
-            fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
-                // eprintln!("quad_to  {:?} {:?} {:?} {:?}", x1, y1, x, y);
-                self.canvas.quadratic_curve_to(x1.into(), y1.into(), x.into(), y.into(),)
-            }
-
-            fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
-                // eprintln!("curve_to {:?} {:?} {:?} {:?} {:?} {:?}", x1, y1, x2, y2, x, y);
-                self.canvas.bezier_curve_to(x1.into(), y1.into(), x2.into(), y2.into(), x.into(), y.into(),)
-            }
-
-            fn close(&mut self) {
-                // eprintln!("close");
-                self.canvas.close_path();
-            }
-
-        }
-
-        let mut builder = Builder {canvas,};
-
-        font.font().outline_glyph(into(gid), &mut builder);
-        builder.fill();
-        canvas.restore();
-
-    }
-}
-
 fn rgba_u8_to_hex_string(color : rex::RGBA) -> String {
     let rex::RGBA(r,g,b,a,) = color;
     format!("#{:02X}{:02X}{:02X}{:02X}", r, g, b, a)
@@ -0,0 +1,139 @@
+use rex::Renderer;
+use rex::font::common::GlyphId;
+use web_sys::{CanvasRenderingContext2d, OffscreenCanvasRenderingContext2d,};
+use wasm_bindgen::prelude::*;
+use std::collections::HashMap;
+
+use crate::error::{AppError, AppResult};
+use crate::geometry::BBox;
+use crate::render::{layout_and_size, render_layout, glyph_to_char_table, GlyphAsTextUtilities, RenderingView};
+use super::canvas::{CanvasContext, OffscreenCanvasContext};
+use super::{Context, ScalePolicy, PNG_PIXELS_PER_POINT};
+
+
+
+impl Context {
+    /// Sets up "glyph as text" rendering on a canvas backend, if enabled.
+    fn glyph_to_char_table(&self, formula : &str) -> AppResult<Option<(HashMap<GlyphId, char>, String)>> {
+        if !self.glyph_as_text {
+            return Ok(None);
+        }
+        let Some(font_name) = self.math_font().get_font_family_name() 
+        else { return Ok(None); };
+        let nodes = self.custom_cmd.parse_formula(formula)?;
+        Ok(Some((glyph_to_char_table(&nodes, self.math_font()), font_name)))
+    }
+}
+
+
+#[wasm_bindgen]
+pub fn render_formula_to_offscreen_canvas_js_err(
+    context    : &Context,
+    formula    : &str,
+    // since we can't know what size the formula will be prior to calling 'layout'
+    // the canvas is created by a JS function which takes two arguments
+    make_new_canvas : &js_sys::Function,
+) -> Result<(), String> {
+    render_formula_to_offscreen_canvas(context, formula, make_new_canvas).map_err(|e| {
+        e.to_string()
+    })
+}
+
+fn render_formula_to_offscreen_canvas(
+    context    : &Context,
+    formula    : &str,
+    canvas_with_size : &js_sys::Function,
+)  -> Result<(), AppError> {
+    let math_font = context.math_font();
+    let formula = context.styled_formula(formula);
+    // as in 'render_formula_to_png', the device pixel ratio is left out: the canvas is for export, not display
+    let font_size = context.font_size * PNG_PIXELS_PER_POINT;
+
+    let (layout, formula_metrics) = layout_and_size(math_font, font_size, &formula, &context.custom_cmd)?;
+
+    let width  = formula_metrics.bbox.width();
+    let height = formula_metrics.bbox.height();
+    let canvas_context : OffscreenCanvasRenderingContext2d = 
+        canvas_with_size
+        .call2(&JsValue::NULL, &JsValue::from_f64(width), &JsValue::from_f64(height),)?
+        .dyn_into()
+        .map_err(|_| AppError::JsError("the callback did not return an 'OffscreenCanvasRenderingContext2D'".to_string()))?
+    ;
+
+
+    canvas_context.translate(0., height + formula_metrics.baseline)?;
+
+    let mut backend = OffscreenCanvasContext::new(&canvas_context);
+    backend.default_color = context.color;
+    if let Some((glyph_to_char_table, font_name)) = context.glyph_to_char_table(&formula)? {
+        backend.glyph_as_text(glyph_to_char_table, &font_name);
+    }
+    Renderer::new().render(&layout, &mut backend);
+
+
+    Ok(())
+}
+
+#[wasm_bindgen]
+pub fn render_formula_to_canvas_js_err(
+    context : &Context,
+    formula : &str, 
+    canvas  : &CanvasRenderingContext2d
+) -> Result<(), String> {
+    render_formula_to_canvas(context, formula, canvas).map_err(|e| {
+        e.to_string()
+    })
+}
+
+
+fn render_formula_to_canvas(
+    context : &Context,
+    formula : &str, 
+    canvas  : &CanvasRenderingContext2d
+) -> AppResult<()> {
+    let math_font = context.math_font();
+    let formula = context.styled_formula(formula);
+    let mut canvas_context = CanvasContext::new(canvas);
+    canvas_context.default_color = context.color;
+    if let Some((glyph_to_char_table, font_name)) = context.glyph_to_char_table(&formula)? {
+        canvas_context.glyph_as_text(glyph_to_char_table, &font_name);
+    }
+    let canvas_size = get_canvas_size(&canvas_context);
+    canvas_context.rendering_context.clear_rect(0., 0., canvas_size.0, canvas_size.1);
+    let (layout, formula_metrics) = layout_and_size(math_font, context.font_size, &formula, &context.custom_cmd)?;
+
+    // Size of the canvas in CSS pixels, the unit of the font size
+    let ratio = context.device_pixel_ratio;
+    let css_size = (canvas_size.0 / ratio, canvas_size.1 / ratio);
+    let overflows = formula_metrics.bbox.width() > css_size.0 || formula_metrics.bbox.height() > css_size.1;
+
+    match context.scale_policy {
+        ScalePolicy::Fit => 
+            render_layout(&mut canvas_context, Some(canvas_size), &formula_metrics, layout)?,
+        ScalePolicy::Shrink if overflows => 
+            render_layout(&mut canvas_context, Some(canvas_size), &formula_metrics, layout)?,
+        ScalePolicy::Fixed | ScalePolicy::Shrink => {
+            canvas_context.save()?;
+            canvas_context.scale(ratio, ratio)?;
+            center(formula_metrics.bbox, &mut canvas_context, css_size)?;
+            render_layout(&mut canvas_context, None, &formula_metrics, layout)?;
+            canvas_context.restore()?;
+        },
+    }
+    Ok(())
+}
+
+/// Puts the center of the bounding box at the center of the canvas, without scaling.
+fn center<C : RenderingView>(bbox : BBox, context : &mut C, canvas_size : (f64, f64)) -> AppResult<()> {
+    let BBox { x_min, y_min, x_max, y_max } = bbox;
+    let midx = 0.5 * (x_min + x_max);
+    let midy = 0.5 * (y_min + y_max);
+    context.translate(0.5 * canvas_size.0 - midx, 0.5 * canvas_size.1 - midy)
+}
+
+fn get_canvas_size(context: &CanvasContext) -> (f64, f64) {
+    let width  = context.rendering_context.canvas().unwrap().width() as f64;
+    let height = context.rendering_context.canvas().unwrap().height() as f64;
+    let canvas_size = (width, height,);
+    canvas_size
+}
@@ -1,12 +1,13 @@
 
 use std::convert::TryInto;
 
-use owned_ttf_parser::{math::GlyphPart, LazyArray16,OutlineBuilder};
+use owned_ttf_parser::{math::GlyphPart, LazyArray16, OutlineBuilder, AsFaceRef};
 
 
 use rex::{font::{FontConstants, VariantGlyph, common::{GlyphInstruction, GlyphId}, Direction, Glyph}, error::FontError, dimensions::units::Ratio};
 use rex::dimensions::Unit;
 use rex::dimensions::units::{Em, FUnit};
+use self_cell::self_cell;
 
 
 
@@ -22,7 +23,10 @@ pub fn into(glyph_id : GlyphId) -> owned_ttf_parser::GlyphId {
 /// A wrapper around 'owned_ttf_parser::Face' which caches some of the needed values.
 /// This wrapper implements the 'MathFont' trait needed to do the layout and rendering o
 pub struct TtfMathFont<'a, 'b> {
-    math: owned_ttf_parser::math::Table<'a>,
+    glyph_info: Option<owned_ttf_parser::math::GlyphInfo<'a>>,
+    /// Constants of the MATH table, converted to ems once and for all
+    constants: Option<FontConstants>,
+    variants: Option<owned_ttf_parser::math::Variants<'a>>,
     font: & 'b owned_ttf_parser::Face<'a>,
     font_matrix: owned_ttf_parser::cff::Matrix,
 }
@@ -45,8 +49,11 @@ impl<'a, 'b> TtfMathFont<'a, 'b> {
                 tx: 0., ty: 0.,
             };
         };
+        let font_units_to_em = Unit::<Ratio<Em, FUnit>>::new(font_matrix.sx as f64);
         Ok(Self { 
-            math, 
+            glyph_info: math.glyph_info, 
+            constants: math.constants.map(|constants| font_constants(constants, font_units_to_em)), 
+            variants: math.variants, 
             font,
             font_matrix,
         }) 
@@ -64,9 +71,34 @@ impl<'a, 'b> TtfMathFont<'a, 'b> {
 }
 
 
+type MathFontRef<'a> = TtfMathFont<'a, 'a>;
+
+self_cell!(
+    /// An 'OwnedFace' together with its parsed MATH table, so that the latter need not be rebuilt on every render.
+    pub struct LoadedFont {
+        owner: owned_ttf_parser::OwnedFace,
+
+        #[covariant]
+        dependent: MathFontRef,
+    }
+);
+
+impl LoadedFont {
+    /// Parses the MATH table of the face.
+    /// Fails if font has no MATH table.
+    pub fn load(face : owned_ttf_parser::OwnedFace) -> Result<Self, FontError> {
+        Self::try_new(face, |face| TtfMathFont::new(face.as_face_ref()))
+    }
+
+    pub fn math_font(&self) -> &TtfMathFont<'_, '_> {
+        self.borrow_dependent()
+    }
+}
+
+
 impl<'a, 'b> TtfMathFont<'a, 'b> {
     fn safe_italics(&self, glyph_id : GlyphId) -> Option<i16> {
-        let value = self.math.glyph_info?
+        let value = self.glyph_info?
             .italic_corrections?
             .get(into(glyph_id))?
             .value;
@@ -74,77 +106,75 @@ impl<'a, 'b> TtfMathFont<'a, 'b> {
     }
 
     fn safe_attachment(&self, glyph_id : GlyphId) -> Option<i16> {
-        // TODO : cache GlyphInfo table & constants
-        let value = self.math.glyph_info?
+        let value = self.glyph_info?
             .top_accent_attachments?
             .get(into(glyph_id))?
             .value;
         Some(value)
     }
+}
 
-    fn safe_constants(&self, font_units_to_em : Unit<Ratio<Em, FUnit>>) -> Option<FontConstants> {
-        // perhaps cache : GlyphInfo table
-        let math_constants = self.math.constants?;
-        let em = |v: f64| -> Unit<Em> { Unit::<FUnit>::new(v) * font_units_to_em };
+/// Converts the constants of the MATH table to ems
+fn font_constants(math_constants : owned_ttf_parser::math::Constants<'_>, font_units_to_em : Unit<Ratio<Em, FUnit>>) -> FontConstants {
+    let em = |v: f64| -> Unit<Em> { Unit::<FUnit>::new(v) * font_units_to_em };
 
 
-        Some(FontConstants {
-            subscript_shift_down:        em(math_constants.subscript_top_max().value.into()),
-            subscript_top_max:           em(math_constants.subscript_top_max().value.into()),
-            subscript_baseline_drop_min: em(math_constants.subscript_baseline_drop_min().value.into()),
-            
-            superscript_baseline_drop_max: em(math_constants.superscript_baseline_drop_max().value.into()),
-            superscript_bottom_min:        em(math_constants.superscript_bottom_min().value.into()),
-            superscript_shift_up_cramped:  em(math_constants.superscript_shift_up_cramped().value.into()),
-            superscript_shift_up:          em(math_constants.superscript_shift_up().value.into()),
-            sub_superscript_gap_min:       em(math_constants.sub_superscript_gap_min().value.into()),
+    FontConstants {
+        subscript_shift_down:        em(math_constants.subscript_top_max().value.into()),
+        subscript_top_max:           em(math_constants.subscript_top_max().value.into()),
+        subscript_baseline_drop_min: em(math_constants.subscript_baseline_drop_min().value.into()),
+        
+        superscript_baseline_drop_max: em(math_constants.superscript_baseline_drop_max().value.into()),
+        superscript_bottom_min:        em(math_constants.superscript_bottom_min().value.into()),
+        superscript_shift_up_cramped:  em(math_constants.superscript_shift_up_cramped().value.into()),
+        superscript_shift_up:          em(math_constants.superscript_shift_up().value.into()),
+        sub_superscript_gap_min:       em(math_constants.sub_superscript_gap_min().value.into()),
 
-            upper_limit_baseline_rise_min: em(math_constants.upper_limit_baseline_rise_min().value.into()),
-            upper_limit_gap_min:           em(math_constants.upper_limit_gap_min().value.into()),
-            lower_limit_gap_min:           em(math_constants.lower_limit_gap_min().value.into()),
-            lower_limit_baseline_drop_min: em(math_constants.lower_limit_baseline_drop_min().value.into()),
+        upper_limit_baseline_rise_min: em(math_constants.upper_limit_baseline_rise_min().value.into()),
+        upper_limit_gap_min:           em(math_constants.upper_limit_gap_min().value.into()),
+        lower_limit_gap_min:           em(math_constants.lower_limit_gap_min().value.into()),
+        lower_limit_baseline_drop_min: em(math_constants.lower_limit_baseline_drop_min().value.into()),
 
-            fraction_rule_thickness:                       em(math_constants.fraction_rule_thickness().value.into()),
-            fraction_numerator_display_style_shift_up:     em(math_constants.fraction_numerator_display_style_shift_up().value.into()),
-            fraction_denominator_display_style_shift_down: em(math_constants.fraction_denominator_display_style_shift_down().value.into()),
-            fraction_num_display_style_gap_min:            em(math_constants.fraction_num_display_style_gap_min().value.into()),
-            fraction_denom_display_style_gap_min:          em(math_constants.fraction_denom_display_style_gap_min().value.into()),
-            fraction_numerator_shift_up:                   em(math_constants.fraction_numerator_shift_up().value.into()),
-            fraction_denominator_shift_down:               em(math_constants.fraction_denominator_shift_down().value.into()),
-            fraction_numerator_gap_min:                    em(math_constants.fraction_numerator_gap_min().value.into()),
-            fraction_denominator_gap_min:                  em(math_constants.fraction_denominator_gap_min().value.into()),
+        fraction_rule_thickness:                       em(math_constants.fraction_rule_thickness().value.into()),
+        fraction_numerator_display_style_shift_up:     em(math_constants.fraction_numerator_display_style_shift_up().value.into()),
+        fraction_denominator_display_style_shift_down: em(math_constants.fraction_denominator_display_style_shift_down().value.into()),
+        fraction_num_display_style_gap_min:            em(math_constants.fraction_num_display_style_gap_min().value.into()),
+        fraction_denom_display_style_gap_min:          em(math_constants.fraction_denom_display_style_gap_min().value.into()),
+        fraction_numerator_shift_up:                   em(math_constants.fraction_numerator_shift_up().value.into()),
+        fraction_denominator_shift_down:               em(math_constants.fraction_denominator_shift_down().value.into()),
+        fraction_numerator_gap_min:                    em(math_constants.fraction_numerator_gap_min().value.into()),
+        fraction_denominator_gap_min:                  em(math_constants.fraction_denominator_gap_min().value.into()),
 
-            axis_height:        em(math_constants.axis_height().value.into()),
-            accent_base_height: em(math_constants.accent_base_height().value.into()),
+        axis_height:        em(math_constants.axis_height().value.into()),
+        accent_base_height: em(math_constants.accent_base_height().value.into()),
 
-            delimited_sub_formula_min_height: em(math_constants.delimited_sub_formula_min_height().into()),
+        delimited_sub_formula_min_height: em(math_constants.delimited_sub_formula_min_height().into()),
 
-            display_operator_min_height: em(math_constants.display_operator_min_height().into()),
+        display_operator_min_height: em(math_constants.display_operator_min_height().into()),
 
-            radical_display_style_vertical_gap: em(math_constants.radical_display_style_vertical_gap().value.into()),
-            radical_vertical_gap:               em(math_constants.radical_vertical_gap().value.into()),
-            radical_rule_thickness:             em(math_constants.radical_rule_thickness().value.into()),
-            radical_extra_ascender:             em(math_constants.radical_extra_ascender().value.into()),
+        radical_display_style_vertical_gap: em(math_constants.radical_display_style_vertical_gap().value.into()),
+        radical_vertical_gap:               em(math_constants.radical_vertical_gap().value.into()),
+        radical_rule_thickness:             em(math_constants.radical_rule_thickness().value.into()),
+        radical_extra_ascender:             em(math_constants.radical_extra_ascender().value.into()),
 
-            stack_display_style_gap_min:      em(math_constants.stack_display_style_gap_min().value.into()),
-            stack_top_display_style_shift_up: em(math_constants.stack_top_display_style_shift_up().value.into()),
-            stack_top_shift_up:               em(math_constants.stack_top_shift_up().value.into()),
-            stack_bottom_shift_down:          em(math_constants.stack_bottom_shift_down().value.into()),
-            stack_gap_min:                    em(math_constants.stack_gap_min().value.into()),
+        stack_display_style_gap_min:      em(math_constants.stack_display_style_gap_min().value.into()),
+        stack_top_display_style_shift_up: em(math_constants.stack_top_display_style_shift_up().value.into()),
+        stack_top_shift_up:               em(math_constants.stack_top_shift_up().value.into()),
+        stack_bottom_shift_down:          em(math_constants.stack_bottom_shift_down().value.into()),
+        stack_gap_min:                    em(math_constants.stack_gap_min().value.into()),
 
-            delimiter_factor: 0.901,
-            delimiter_short_fall: Unit::<Em>::new(0.1),
-            null_delimiter_space: Unit::<Em>::new(0.1),
+        delimiter_factor: 0.901,
+        delimiter_short_fall: Unit::<Em>::new(0.1),
+        null_delimiter_space: Unit::<Em>::new(0.1),
 
 
-            underbar_vertical_gap:    em(math_constants.underbar_vertical_gap().value.into()),
-            underbar_rule_thickness:  em(math_constants.underbar_rule_thickness().value.into()),
-            underbar_extra_descender: em(math_constants.underbar_extra_descender().value.into()),
+        underbar_vertical_gap:    em(math_constants.underbar_vertical_gap().value.into()),
+        underbar_rule_thickness:  em(math_constants.underbar_rule_thickness().value.into()),
+        underbar_extra_descender: em(math_constants.underbar_extra_descender().value.into()),
 
-            script_percent_scale_down: 0.01 * f64::from(math_constants.script_percent_scale_down()),
-            script_script_percent_scale_down: 0.01 * f64::from(math_constants.script_script_percent_scale_down()),
+        script_percent_scale_down: 0.01 * f64::from(math_constants.script_percent_scale_down()),
+        script_script_percent_scale_down: 0.01 * f64::from(math_constants.script_script_percent_scale_down()),
 
-        })
     }
 }
 
@@ -159,15 +189,16 @@ impl<'a, 'b> rex::font::MathFont for TtfMathFont<'a,'b> {
         self.safe_attachment(glyph_id).unwrap_or_default()
     }
 
-    fn constants(&self, font_units_to_em: Unit<Ratio<Em, FUnit>>) -> FontConstants {
-        self.safe_constants(font_units_to_em).unwrap()
+    fn constants(&self, _font_units_to_em: Unit<Ratio<Em, FUnit>>) -> FontConstants {
+        // ReX asks for the constants with the conversion factor of this very font, cf. 'font_units_to_em'
+        self.constants.clone().unwrap()
     }
 
     fn horz_variant(&self, gid: GlyphId, width: rex::dimensions::Unit<FUnit>) -> rex::font::common::VariantGlyph {
         // NOTE: The following is an adaptation of the corresponding code in the crate "font"
         // NOTE: bizarrely, the code for horizontal variant is not isomorphic to the code for vertical variant ; here, I've simply adapted the vertical variant code
         // TODO: figure out why horiz_variant uses 'greatest_lower_bound' and vert_variant uses 'smallest_lowerè_bound'
-        let variants = match self.math.variants {
+        let variants = match self.variants {
             Some(variants) => variants,
             None => return VariantGlyph::Replacement(gid),
         };
@@ -208,7 +239,7 @@ impl<'a, 'b> rex::font::MathFont for TtfMathFont<'a,'b> {
     fn vert_variant(&self, gid: GlyphId, height: rex::dimensions::Unit<FUnit>) -> rex::font::common::VariantGlyph {
         // NOTE: The following is an adaptation of the corresponding code in the crate "font"
 
-        let variants = match self.math.variants {
+        let variants = match self.variants {
             Some(variants) => variants,
             None => return VariantGlyph::Replacement(gid),
         };
@@ -278,7 +309,7 @@ impl<'a, 'b> rex::font::MathFont for TtfMathFont<'a,'b> {
     }
 
     fn kern_for(&self, glyph_id : GlyphId, height : Unit<FUnit>, side : rex::font::kerning::Corner) -> Option<Unit<FUnit>> {
-        let record = self.math.glyph_info?.kern_infos?.get(into(glyph_id))?;
+        let record = self.glyph_info?.kern_infos?.get(into(glyph_id))?;
 
         let table = match side {
             rex::font::kerning::Corner::TopRight    => record.top_right.as_ref(),
@@ -0,0 +1,552 @@
+use rex::{font::common::GlyphId, Backend, Cursor, FontBackend, GraphicsBackend};
+
+use crate::error::{AppError, AppResult};
+use crate::geometry::Metrics;
+use crate::svg::{GivesOutline, OutlineBuilder};
+
+
+
+/// Maximal distance, in pixels, between a curve and the line segments approximating it
+const FLATTENING_TOLERANCE : f32 = 0.1;
+
+/// Largest number of pixels of an image (each pixel takes 16 bytes while rendering)
+const MAX_PIXELS : usize = 1 << 24;
+
+/// Software renderer drawing formulas in an RGBA image, for environments without a canvas (Web Workers, Node).
+pub struct RasterContext {
+    image : Image,
+    /// Translation from layout coordinates to pixel coordinates
+    offset : (f64, f64),
+    color_stack : Vec<rex::RGBA>,
+    default_color : rex::RGBA,
+}
+
+impl RasterContext {
+    /// Creates an image fitting the bounding box of the formula.
+    /// One unit of the layout is one pixel.
+    /// Fails if the image would have more than 'MAX_PIXELS' pixels.
+    pub fn new(metrics : &Metrics, default_color : rex::RGBA) -> AppResult<Self> {
+        let bbox = metrics.bbox;
+        // 'as' saturates, so infinite or huge boxes end up above the limit too
+        let width  = (bbox.width().ceil()  as usize).max(1);
+        let height = (bbox.height().ceil() as usize).max(1);
+        if width.checked_mul(height).map_or(true, |pixels| pixels > MAX_PIXELS) {
+            return Err(AppError::ImageTooLarge(width, height));
+        }
+        Ok(Self {
+            image : Image::new(width, height),
+            offset : (- bbox.x_min, - bbox.y_min),
+            color_stack : Vec::new(),
+            default_color,
+        })
+    }
+
+    pub fn finalize(self) -> Image {
+        self.image
+    }
+
+    fn current_color(&self) -> rex::RGBA {
+        self.color_stack.last().copied().unwrap_or(self.default_color)
+    }
+}
+
+impl<T : GivesOutline> Backend<T> for RasterContext {}
+
+impl GraphicsBackend for RasterContext {
+    fn rule(&mut self, pos: Cursor, width: f64, height: f64) {
+        let x0 = (pos.x + self.offset.0) as f32;
+        let y0 = (pos.y + self.offset.1) as f32;
+        let x1 = x0 + width  as f32;
+        let y1 = y0 + height as f32;
+
+        let mut path = Path::default();
+        path.move_to(x0, y0);
+        path.line_to(x1, y0);
+        path.line_to(x1, y1);
+        path.line_to(x0, y1);
+        path.close();
+        self.image.fill(&path, self.current_color());
+    }
+
+    fn begin_color(&mut self, color: rex::RGBA) {
+        self.color_stack.push(color);
+    }
+
+    fn end_color(&mut self) {
+        self.color_stack.pop();
+    }
+}
+
+impl<T : GivesOutline> FontBackend<T> for RasterContext {
+    fn symbol(&mut self, pos: Cursor, gid: GlyphId, scale: f64, font: &T) {
+        let (sx, sy) = font.font_scale();
+
+        struct Builder {
+            path  : Path,
+            tx : f32, ty : f32,
+            sx : f32, sy : f32,
+        }
+
+        impl Builder {
+            fn transform(&self, x : f32, y : f32) -> (f32, f32) {
+                (self.tx + self.sx * x, self.ty + self.sy * y)
+            }
+        }
+
+        impl OutlineBuilder for Builder {
+            fn move_to(&mut self, x: f32, y: f32) {
+                let (x, y) = self.transform(x, y);
+                self.path.move_to(x, y);
+            }
+
+            fn line_to(&mut self, x: f32, y: f32) {
+                let (x, y) = self.transform(x, y);
+                self.path.line_to(x, y);
+            }
+
+            fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+                let (x1, y1) = self.transform(x1, y1);
+                let (x, y)   = self.transform(x, y);
+                self.path.quad_to(x1, y1, x, y);
+            }
+
+            fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+                let (x1, y1) = self.transform(x1, y1);
+                let (x2, y2) = self.transform(x2, y2);
+                let (x, y)   = self.transform(x, y);
+                self.path.curve_to(x1, y1, x2, y2, x, y);
+            }
+
+            fn close(&mut self) {
+                self.path.close();
+            }
+        }
+
+        let mut builder = Builder {
+            path : Path::default(),
+            tx : (pos.x + self.offset.0) as f32,
+            ty : (pos.y + self.offset.1) as f32,
+            sx :   (scale * f64::from(sx)) as f32,
+            sy : - (scale * f64::from(sy)) as f32,
+        };
+        font.outline_glyph(gid, &mut builder);
+        builder.path.close();
+        let color = self.current_color();
+        self.image.fill(&builder.path, color);
+    }
+}
+
+
+
+/// A closed polygon, with curves already flattened into line segments.
+#[derive(Debug, Default)]
+struct Path {
+    lines : Vec<((f32, f32), (f32, f32))>,
+    start : (f32, f32),
+    current : (f32, f32),
+}
+
+impl Path {
+    fn move_to(&mut self, x : f32, y : f32) {
+        self.close();
+        self.start = (x, y);
+        self.current = (x, y);
+    }
+
+    fn line_to(&mut self, x : f32, y : f32) {
+        self.lines.push((self.current, (x, y)));
+        self.current = (x, y);
+    }
+
+    fn quad_to(&mut self, x1 : f32, y1 : f32, x : f32, y : f32) {
+        let (x0, y0) = self.current;
+        let deviation = f32::hypot(x0 - 2. * x1 + x, y0 - 2. * y1 + y);
+        let n_segments = segment_count(deviation);
+        for i in 1 ..= n_segments {
+            let t = i as f32 / n_segments as f32;
+            let u = 1. - t;
+            self.line_to(
+                u * u * x0 + 2. * u * t * x1 + t * t * x,
+                u * u * y0 + 2. * u * t * y1 + t * t * y,
+            );
+        }
+    }
+
+    fn curve_to(&mut self, x1 : f32, y1 : f32, x2 : f32, y2 : f32, x : f32, y : f32) {
+        let (x0, y0) = self.current;
+        let deviation = f32::max(
+            f32::hypot(x0 - 2. * x1 + x2, y0 - 2. * y1 + y2),
+            f32::hypot(x1 - 2. * x2 + x,  y1 - 2. * y2 + y),
+        );
+        let n_segments = segment_count(1.5 * deviation);
+        for i in 1 ..= n_segments {
+            let t = i as f32 / n_segments as f32;
+            let u = 1. - t;
+            self.line_to(
+                u * u * u * x0 + 3. * u * u * t * x1 + 3. * u * t * t * x2 + t * t * t * x,
+                u * u * u * y0 + 3. * u * u * t * y1 + 3. * u * t * t * y2 + t * t * t * y,
+            );
+        }
+    }
+
+    fn close(&mut self) {
+        if self.current != self.start {
+            let (x, y) = self.start;
+            self.line_to(x, y);
+        }
+    }
+}
+
+/// Number of line segments needed to approximate a curve whose control polygon deviates from a straight line by 'deviation'
+fn segment_count(deviation : f32) -> usize {
+    ((deviation / FLATTENING_TOLERANCE).sqrt().ceil() as usize).clamp(1, 100)
+}
+
+
+
+/// RGBA image, with premultiplied alpha.
+pub struct Image {
+    width  : usize,
+    height : usize,
+    pixels : Vec<[f32; 4]>,
+}
+
+impl Image {
+    fn new(width : usize, height : usize) -> Self {
+        Self { width, height, pixels : vec![[0.; 4]; width * height] }
+    }
+
+    /// Fills the path with the color, with anti-aliasing.
+    /// Coverage is computed by accumulating the signed area under each edge, as in font-rs.
+    fn fill(&mut self, path : &Path, color : rex::RGBA) {
+        if path.lines.is_empty() {
+            return;
+        }
+
+        // Only rasterize the part of the image covered by the path
+        let (mut x_min, mut y_min, mut x_max, mut y_max) = (f32::INFINITY, f32::INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for &((x0, y0), (x1, y1)) in &path.lines {
+            x_min = x_min.min(x0).min(x1);
+            x_max = x_max.max(x0).max(x1);
+            y_min = y_min.min(y0).min(y1);
+            y_max = y_max.max(y0).max(y1);
+        }
+        let left   = (x_min.floor().max(0.) as usize).min(self.width);
+        let top    = (y_min.floor().max(0.) as usize).min(self.height);
+        let right  = (x_max.ceil().max(0.) as usize).min(self.width);
+        let bottom = (y_max.ceil().max(0.) as usize).min(self.height);
+        if left >= right || top >= bottom {
+            return;
+        }
+
+        // One extra column on each side, since edges may extend past the image
+        let mut accumulator = Accumulator::new(right - left + 2, bottom - top);
+        for &((x0, y0), (x1, y1)) in &path.lines {
+            accumulator.line(
+                (x0 - left as f32 + 1., y0 - top as f32),
+                (x1 - left as f32 + 1., y1 - top as f32),
+            );
+        }
+
+        let rex::RGBA(r, g, b, a) = color;
+        let alpha = f32::from(a) / 255.;
+        let source = [
+            f32::from(r) / 255. * alpha,
+            f32::from(g) / 255. * alpha,
+            f32::from(b) / 255. * alpha,
+            alpha,
+        ];
+
+        for row in 0 .. bottom - top {
+            let mut area = 0.;
+            for column in 0 .. accumulator.width {
+                area += accumulator.cells[row * accumulator.width + column];
+                // skip the extra columns
+                let x = (left + column).wrapping_sub(1);
+                if column == 0 || x >= right {
+                    continue;
+                }
+                let coverage = area.abs().min(1.);
+                if coverage == 0. {
+                    continue;
+                }
+
+                // "source over" compositing
+                let pixel = &mut self.pixels[(top + row) * self.width + x];
+                let source_alpha = source[3] * coverage;
+                for channel in 0 .. 4 {
+                    pixel[channel] = source[channel] * coverage + pixel[channel] * (1. - source_alpha);
+                }
+            }
+        }
+    }
+
+    /// Encodes the image as a PNG file (8 bit RGBA, no interlacing).
+    pub fn encode_png(&self) -> Vec<u8> {
+        const SIGNATURE : [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+        const BIT_DEPTH : u8 = 8;
+        const COLOR_TYPE_RGBA : u8 = 6;
+        const COMPRESSION_LEVEL : u8 = 6;
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width  as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // bit depth, color type, compression method, filter method, interlace method
+        header.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_RGBA, 0, 0, 0]);
+
+        let mut raw_data = Vec::with_capacity(self.height * (1 + 4 * self.width));
+        for row in self.pixels.chunks(self.width) {
+            // no filter
+            raw_data.push(0);
+            for &[r, g, b, a] in row {
+                let unpremultiply = |channel : f32| if a > 0. { channel / a } else { 0. };
+                raw_data.extend_from_slice(&[
+                    to_u8(unpremultiply(r)),
+                    to_u8(unpremultiply(g)),
+                    to_u8(unpremultiply(b)),
+                    to_u8(a),
+                ]);
+            }
+        }
+        let compressed_data = miniz_oxide::deflate::compress_to_vec_zlib(&raw_data, COMPRESSION_LEVEL);
+
+        let mut png = SIGNATURE.to_vec();
+        write_chunk(&mut png, b"IHDR", &header);
+        write_chunk(&mut png, b"IDAT", &compressed_data);
+        write_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn to_u8(value : f32) -> u8 {
+    (value.clamp(0., 1.) * 255.).round() as u8
+}
+
+fn write_chunk(png : &mut Vec<u8>, chunk_type : &[u8; 4], data : &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let crc_start = png.len();
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[crc_start ..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// CRC-32 as specified by PNG (ISO 3309)
+fn crc32(bytes : &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0 .. 8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+
+
+/// Grid of signed area contributions ; the coverage of a pixel is the running sum of the cells of its row up to it.
+struct Accumulator {
+    width  : usize,
+    height : usize,
+    cells  : Vec<f32>,
+}
+
+impl Accumulator {
+    fn new(width : usize, height : usize) -> Self {
+        Self { width, height, cells : vec![0.; width * height] }
+    }
+
+    fn add(&mut self, row : usize, column : i64, value : f32) {
+        let column = column.clamp(0, self.width as i64 - 1) as usize;
+        self.cells[row * self.width + column] += value;
+    }
+
+    fn line(&mut self, p0 : (f32, f32), p1 : (f32, f32)) {
+        if p0.1 == p1.1 {
+            return;
+        }
+        let (direction, (x0, y0), (x1, y1)) = if p0.1 < p1.1 { (1., p0, p1) } else { (-1., p1, p0) };
+        let dxdy = (x1 - x0) / (y1 - y0);
+
+        let mut x = x0;
+        if y0 < 0. {
+            x -= y0 * dxdy;
+        }
+        let first_row = y0.max(0.) as usize;
+        let last_row  = (y1.ceil().max(0.) as usize).min(self.height);
+
+        for row in first_row .. last_row {
+            let top = row as f32;
+            let dy = f32::min(top + 1., y1) - f32::max(top, y0);
+            let x_next = x + dxdy * dy;
+            let d = dy * direction;
+            let (left, right) = if x < x_next { (x, x_next) } else { (x_next, x) };
+
+            let left_floor = left.floor();
+            let left_column = left_floor as i64;
+            let right_ceil = right.ceil();
+            let right_column = right_ceil as i64;
+
+            if right_column <= left_column + 1 {
+                // the edge stays within one pixel of the row
+                let x_mid = 0.5 * (x + x_next) - left_floor;
+                self.add(row, left_column,     d - d * x_mid);
+                self.add(row, left_column + 1, d * x_mid);
+            }
+            else {
+                let inverse_slope = (right - left).recip();
+                let left_fraction = left - left_floor;
+                let area_left = 0.5 * inverse_slope * (1. - left_fraction) * (1. - left_fraction);
+                let right_fraction = right - right_ceil + 1.;
+                let area_right = 0.5 * inverse_slope * right_fraction * right_fraction;
+
+                self.add(row, left_column, d * area_left);
+                if right_column == left_column + 2 {
+                    self.add(row, left_column + 1, d * (1. - area_left - area_right));
+                }
+                else {
+                    let area_first = inverse_slope * (1.5 - left_fraction);
+                    self.add(row, left_column + 1, d * (area_first - area_left));
+                    for column in left_column + 2 .. right_column - 1 {
+                        self.add(row, column, d * inverse_slope);
+                    }
+                    let area_before_last = area_first + (right_column - left_column - 3) as f32 * inverse_slope;
+                    self.add(row, right_column - 1, d * (1. - area_before_last - area_right));
+                }
+                self.add(row, right_column, d * area_right);
+            }
+            x = x_next;
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::BBox;
+
+    const BLACK : rex::RGBA = rex::RGBA(0, 0, 0, 255);
+
+    fn polygon(points : &[(f32, f32)]) -> Path {
+        let mut path = Path::default();
+        path.move_to(points[0].0, points[0].1);
+        for &(x, y) in &points[1 ..] {
+            path.line_to(x, y);
+        }
+        path.close();
+        path
+    }
+
+    fn coverage(image : &Image, x : usize, y : usize) -> f32 {
+        image.pixels[y * image.width + x][3]
+    }
+
+    fn assert_close(actual : f32, expected : f32, x : usize, y : usize) {
+        assert!((actual - expected).abs() < 1e-4, "pixel ({}, {}) : expected {}, got {}", x, y, expected, actual);
+    }
+
+    #[test]
+    fn square_on_pixel_boundaries() {
+        let mut image = Image::new(4, 4);
+        image.fill(&polygon(&[(1., 1.), (3., 1.), (3., 3.), (1., 3.)]), BLACK);
+        for y in 0 .. 4 {
+            for x in 0 .. 4 {
+                let inside = (1 ..= 2).contains(&x) && (1 ..= 2).contains(&y);
+                assert_close(coverage(&image, x, y), if inside { 1. } else { 0. }, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn square_across_pixels() {
+        let mut image = Image::new(3, 3);
+        image.fill(&polygon(&[(0.5, 0.5), (2.5, 0.5), (2.5, 2.5), (0.5, 2.5)]), BLACK);
+        for y in 0 .. 3 {
+            for x in 0 .. 3 {
+                let edges = [x, y].iter().filter(|&&c| c != 1).count();
+                assert_close(coverage(&image, x, y), 0.5f32.powi(edges as i32), x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn triangle() {
+        let mut image = Image::new(4, 4);
+        // the hypotenuse goes through the diagonal of pixels with x + y = 3
+        image.fill(&polygon(&[(0., 0.), (4., 0.), (0., 4.)]), BLACK);
+        let mut total = 0.;
+        for y in 0 .. 4 {
+            for x in 0 .. 4 {
+                let expected = match (x + y).cmp(&3) {
+                    std::cmp::Ordering::Less    => 1.,
+                    std::cmp::Ordering::Equal   => 0.5,
+                    std::cmp::Ordering::Greater => 0.,
+                };
+                assert_close(coverage(&image, x, y), expected, x, y);
+                total += coverage(&image, x, y);
+            }
+        }
+        assert_close(total, 8., 0, 0);
+    }
+
+    #[test]
+    fn winding_does_not_matter() {
+        let mut clockwise = Image::new(4, 4);
+        let mut counter_clockwise = Image::new(4, 4);
+        clockwise.fill(&polygon(&[(0.3, 0.2), (3.7, 1.1), (1.4, 3.9)]), BLACK);
+        counter_clockwise.fill(&polygon(&[(0.3, 0.2), (1.4, 3.9), (3.7, 1.1)]), BLACK);
+        for y in 0 .. 4 {
+            for x in 0 .. 4 {
+                assert_close(coverage(&clockwise, x, y), coverage(&counter_clockwise, x, y), x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        // every PNG ends with this chunk
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+    }
+
+    #[test]
+    fn png_chunks() {
+        let mut image = Image::new(2, 3);
+        image.fill(&polygon(&[(0., 0.), (1., 0.), (1., 1.), (0., 1.)]), rex::RGBA(255, 0, 0, 255));
+        let png = image.encode_png();
+        assert_eq!(&png[.. 8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']);
+
+        let mut chunks = Vec::new();
+        let mut rest = &png[8 ..];
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[.. 4].try_into().unwrap()) as usize;
+            let (typed_data, crc) = rest[4 ..].split_at(4 + length);
+            assert_eq!(crc32(typed_data), u32::from_be_bytes(crc[.. 4].try_into().unwrap()));
+            chunks.push((&typed_data[.. 4], &typed_data[4 ..]));
+            rest = &crc[4 ..];
+        }
+
+        let chunk_types : Vec<_> = chunks.iter().map(|(chunk_type, _)| *chunk_type).collect();
+        assert_eq!(chunk_types, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 2, 0, 0, 0, 3, 8, 6, 0, 0, 0]);
+        let raw_data = miniz_oxide::inflate::decompress_to_vec_zlib(chunks[1].1).unwrap();
+        assert_eq!(raw_data, [
+            0, 255, 0, 0, 255, 0, 0, 0, 0,
+            0,   0, 0, 0,   0, 0, 0, 0, 0,
+            0,   0, 0, 0,   0, 0, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn image_size_is_bounded() {
+        let metrics = |width, height| Metrics { bbox : BBox::new(0., 0., width, height), baseline : 0., font_size : 10. };
+        assert!(RasterContext::new(&metrics(4096., 4096.), BLACK).is_ok());
+        assert!(matches!(RasterContext::new(&metrics(4097., 4096.), BLACK), Err(AppError::ImageTooLarge(4097, 4096))));
+        assert!(RasterContext::new(&metrics(f64::INFINITY, 1.), BLACK).is_err());
+        assert!(RasterContext::new(&metrics(1e300, 1e300), BLACK).is_err());
+    }
+}
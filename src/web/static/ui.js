@@ -1,7 +1,8 @@
 import init, { 
 	init_font, 
+	init_font_from_bytes,
 	render_formula_to_canvas_js_err,
-	render_formula_to_offscreen_canvas_js_err,
+	render_formula_to_png,
 	render_formula_to_svg 
 } from './maths_preview.js';
 
@@ -16,18 +17,23 @@ let format_picker;
 let error_msg;
 let export_options_link;
 let settings_form;
+let font_picker;
+let styfile_input;
 
 async function run() {
 	await init();
 
 	// -- initialize all protagonists
 	wasm_context = init_font();
+	wasm_context.device_pixel_ratio = window.devicePixelRatio;
 	canvas        = document.getElementById("canvas");
 	formula_input = document.getElementById("formula");
 	save_button   = document.getElementById("save");
 	format_picker = document.getElementById("saveFormat");
 	error_msg     = document.getElementById("error");
 	settings_form = document.getElementById("settings_form");
+	font_picker   = document.getElementById("font_file");
+	styfile_input = document.getElementById("styfile");
 	export_options_link   = document.getElementById("export_options_link");
 	export_options_dialog = document.getElementById("export_options_dialog");
 	canvas_context = canvas.getContext("2d");
@@ -57,6 +63,12 @@ async function run() {
 	setSettingsFromContext();
 	settings_form.addEventListener("change", sendSettings);
 
+	// -- Load user-supplied font
+	font_picker.addEventListener("change", loadFont);
+
+	// -- Load custom commands
+	styfile_input.addEventListener("change", sendStyleFile);
+
 }
 
 
@@ -95,17 +107,17 @@ function saveFormula() {
 	}
 }
 
-async function saveFormulaToPng() {
+function saveFormulaToPng() {
 	const formula = formula_input.value;
-	const offscreen = new OffscreenCanvas(1, 1);
-	render_formula_to_offscreen_canvas_js_err(wasm_context, formula, (width, height) => {
-		console.log(width, height);
-		offscreen.width  = Math.ceil(width);
-		offscreen.height = Math.ceil(height);
-		return offscreen.getContext("2d");
-	});
-	const blob = await offscreen.convertToBlob({type : "image/png"});
-	triggerDownload("formula.png", blob);
+	let png_bytes;
+	try {
+		png_bytes = render_formula_to_png(wasm_context, formula);
+	}
+	catch(error) {
+		setError(error.message);
+		return;
+	}
+	triggerDownload("formula.png", new Blob([png_bytes], { type: "image/png" }));
 }
 
 function saveFormulaToSvg() {
@@ -129,6 +141,39 @@ function renderFormula() {
 	}
 }
 
+async function loadFont() {
+	const file = font_picker.files[0];
+	if(!file) {
+		return;
+	}
+	const font_bytes = new Uint8Array(await file.arrayBuffer());
+	let new_context;
+	try {
+		new_context = init_font_from_bytes(font_bytes);
+	}
+	catch(error) {
+		setError(error.message);
+		return;
+	}
+	// release the memory held by the previous font
+	wasm_context.free();
+	wasm_context = new_context;
+	wasm_context.device_pixel_ratio = window.devicePixelRatio;
+	sendSettings();
+	sendStyleFile();
+}
+
+function sendStyleFile() {
+	try {
+		wasm_context.set_style_file(styfile_input.value);
+	}
+	catch(error) {
+		setError(error.message);
+		return;
+	}
+	renderFormula();
+}
+
 function showDialog() {
 	export_options_dialog.show();
 }
@@ -146,7 +191,16 @@ function sendSettings() {
 		glyph_as_text,
 		font_size
 	);
+	try {
+		wasm_context.set_color(String(formData.get("color")));
+		wasm_context.set_style(String(formData.get("style")));
+		wasm_context.set_scale_policy(String(formData.get("scale_policy")));
+	}
+	catch(error) {
+		setError(error.message);
+	}
 	setSettingsFromContext();
+	renderFormula();
 	// // Filling in unchecked checkbox
 	// if(!("glyph_as_text" in formData)) {
 	// 	formData.glyph_as_text = false;
@@ -160,6 +214,10 @@ function setSettingsFromContext() {
 	fillForm(settings_form, {
 		glyph_as_text: wasm_context.glyph_as_text,
 		font_size: wasm_context.font_size,
+		// color inputs don't support transparency
+		color: wasm_context.color.slice(0, 7),
+		style: wasm_context.style,
+		scale_policy: wasm_context.scale_policy,
 	});
 }
 
@@ -225,8 +283,10 @@ function resizeCanvas(){
 	if(height > 0.9 * window.innerHeight) {
 		height = 0.9 * window.innerHeight;
 	}
-	canvas.width  = width;
-	canvas.height = height;
+	// the backing store is in device pixels so that the preview is crisp on HiDPI screens
+	canvas.style.height = height + "px";
+	canvas.width  = Math.round(width  * window.devicePixelRatio);
+	canvas.height = Math.round(height * window.devicePixelRatio);
 }
 
 run();